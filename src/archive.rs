@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::Mutex;
+
+use color_print::{ceprintln, cprintln};
+use strum::IntoEnumIterator;
+use zip::ZipArchive;
+
+use crate::config::AudioRoots;
+use crate::database::AudioSource;
+use crate::helper::{PackIndex, AUDIO_FILE_STEMS};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl actix_web::ResponseError for ArchiveError {}
+
+/// a source served straight out of a zip archive (`<audio>/<source>.zip`).
+/// the central directory is indexed once at startup, entries are read on request.
+pub struct ZipPack {
+    archive: Mutex<ZipArchive<BufReader<File>>>,
+    pub index: PackIndex,
+    /// path relative to the source folder -> index of the entry in the archive
+    entries: HashMap<String, usize>,
+}
+
+impl ZipPack {
+    pub fn open(path: &Path, source: &AudioSource) -> Result<Self, ArchiveError> {
+        let archive = ZipArchive::new(BufReader::new(File::open(path)?))?;
+        let prefix = format!("{source}/");
+        let mut index = PackIndex::default();
+        let mut entries = HashMap::new();
+        for (i, name) in archive.file_names().enumerate() {
+            let is_audio = name
+                .rsplit_once('.')
+                .is_some_and(|(_, ext)| AUDIO_FILE_STEMS.contains(ext));
+            if !is_audio {
+                continue;
+            }
+            // archives of a source folder usually contain the folder itself
+            let rel = name.strip_prefix(&prefix).unwrap_or(name).to_string();
+            index.insert(rel.clone());
+            // `file_names` iterates in archive order, which is what `by_index` expects
+            entries.insert(rel, i);
+        }
+        Ok(Self {
+            archive: Mutex::new(archive),
+            index,
+            entries,
+        })
+    }
+
+    /// decompresses a single entry. `None` if the archive doesn't have it.
+    pub fn read(&self, path: &str) -> Result<Option<Vec<u8>>, ArchiveError> {
+        let Some(&i) = self.entries.get(path) else {
+            return Ok(None);
+        };
+        let mut archive = self.archive.lock().unwrap_or_else(|e| e.into_inner());
+        let mut file = archive.by_index(i)?;
+        let mut data = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+}
+
+/// opens every `<audio>/<source>.zip` archive
+pub fn open_zips(roots: &AudioRoots) -> HashMap<AudioSource, ZipPack> {
+    let mut zips = HashMap::new();
+    for source in AudioSource::iter().filter(|s| *s != AudioSource::Other) {
+        let path = roots.get(&source).join(format!("{source}.zip"));
+        if !path.exists() {
+            continue;
+        }
+        match ZipPack::open(&path, &source) {
+            Ok(zip) => {
+                cprintln!(
+                    "<i><g>+</> {} loaded ({} files)</>",
+                    path.display(),
+                    zip.index.len()
+                );
+                zips.insert(source, zip);
+            }
+            Err(e) => ceprintln!("<r>[error]</> failed to open {}: {e}", path.display()),
+        }
+    }
+    zips
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ZipPack;
+    use crate::database::AudioSource;
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    #[test]
+    fn index_and_read_zip() {
+        // per process, so parallel test runs don't share the archive
+        let dir = std::env::temp_dir().join(format!("yas_zip_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("index_and_read.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let opts = SimpleFileOptions::default();
+        zip.start_file("forvo_jp/spk1/本.mp3", opts).unwrap();
+        zip.write_all(b"hon").unwrap();
+        zip.start_file("forvo_jp/readme.txt", opts).unwrap();
+        zip.write_all(b"not audio").unwrap();
+        zip.start_file("media/日本.mp3", opts).unwrap();
+        zip.write_all(b"nihon").unwrap();
+        zip.finish().unwrap();
+
+        let pack = ZipPack::open(&path, &AudioSource::ForvoJp).unwrap();
+        assert_eq!(pack.index.len(), 2);
+        // the source folder is stripped, so paths line up with the /audio/<source>/ urls
+        assert_eq!(pack.index.find("本.mp3", "spk1"), Some("spk1/本.mp3"));
+        assert_eq!(pack.read("spk1/本.mp3").unwrap().unwrap(), b"hon");
+        assert_eq!(pack.read("media/日本.mp3").unwrap().unwrap(), b"nihon");
+        assert_eq!(pack.read("readme.txt").unwrap(), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use color_print::{ceprintln, cprintln};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use strum::IntoEnumIterator;
+
+use crate::config::AudioRoots;
+use crate::database::AudioSource;
+use crate::helper::PackIndex;
+use crate::metadata::collect_audio_files;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlobError {
+    #[error("blob database error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0} already exists, delete it first to re-import")]
+    Exists(String),
+}
+
+/// a source whose audio is stored as BLOBs in a single sqlite file (`<audio>/<source>.db`)
+/// instead of a folder, with the table:
+/// `audio (path TEXT PRIMARY KEY, data BLOB NOT NULL)`
+/// where `path` is relative to the source folder, ex: `media/本.mp3`
+pub struct BlobPack {
+    pool: SqlitePool,
+    pub index: PackIndex,
+}
+
+impl BlobPack {
+    pub async fn open(path: &Path) -> Result<Self, BlobError> {
+        let opts = SqliteConnectOptions::new().filename(path).read_only(true);
+        let pool = SqlitePool::connect_with(opts).await?;
+        let paths: Vec<(String,)> = sqlx::query_as("SELECT path FROM audio")
+            .fetch_all(&pool)
+            .await?;
+        let mut index = PackIndex::default();
+        for (path,) in paths {
+            index.insert(path);
+        }
+        Ok(Self { pool, index })
+    }
+
+    pub async fn get(&self, path: &str) -> Result<Option<Vec<u8>>, BlobError> {
+        let data: Option<(Vec<u8>,)> = sqlx::query_as("SELECT data FROM audio WHERE path = ?")
+            .bind(path)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(data.map(|(d,)| d))
+    }
+}
+
+/// opens every `<audio>/<source>.db` pack
+pub async fn open_packs(roots: &AudioRoots) -> HashMap<AudioSource, BlobPack> {
+    let mut packs = HashMap::new();
+    for source in AudioSource::iter().filter(|s| *s != AudioSource::Other) {
+        let path = roots.get(&source).join(format!("{source}.db"));
+        if !path.exists() {
+            continue;
+        }
+        match BlobPack::open(&path).await {
+            Ok(pack) => {
+                cprintln!(
+                    "<i><g>+</> {} loaded ({} files)</>",
+                    path.display(),
+                    pack.index.len()
+                );
+                packs.insert(source, pack);
+            }
+            Err(e) => ceprintln!("<r>[error]</> failed to open {}: {e}", path.display()),
+        }
+    }
+    packs
+}
+
+/// packs every audio file under `dir` into a new blob database at `out`
+pub async fn import(dir: &Path, out: &Path) -> Result<(), BlobError> {
+    if out.exists() {
+        return Err(BlobError::Exists(out.display().to_string()));
+    }
+    let mut files = Vec::new();
+    collect_audio_files(dir, &mut files)?;
+
+    let opts = SqliteConnectOptions::new()
+        .filename(out)
+        .create_if_missing(true);
+    let pool = SqlitePool::connect_with(opts).await?;
+    sqlx::query(
+        "CREATE TABLE audio
+        (
+            path TEXT PRIMARY KEY,
+            data BLOB NOT NULL
+        )",
+    )
+    .execute(&pool)
+    .await?;
+
+    let mut transaction = pool.begin().await?;
+    for (i, file) in files.iter().enumerate() {
+        let rel = file.strip_prefix(dir).unwrap_or(file);
+        let rel = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        sqlx::query("INSERT OR REPLACE INTO audio (path, data) VALUES (?, ?)")
+            .bind(rel)
+            .bind(std::fs::read(file)?)
+            .execute(&mut *transaction)
+            .await?;
+        if (i + 1) % 10_000 == 0 {
+            cprintln!("  {}/{}", i + 1, files.len());
+        }
+    }
+    transaction.commit().await?;
+    cprintln!(
+        "<g>+</> packed <b>{}</> files into {}",
+        files.len(),
+        out.display()
+    );
+    Ok(())
+}
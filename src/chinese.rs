@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+struct Table {
+    simplified: HashMap<char, char>,
+    traditional: HashMap<char, char>,
+}
+
+/// traditional <-> simplified characters, from `data/zh_variants.txt`
+static TABLE: LazyLock<Table> = LazyLock::new(|| {
+    let mut table = Table {
+        simplified: HashMap::new(),
+        traditional: HashMap::new(),
+    };
+    for line in include_str!("../data/zh_variants.txt").lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(t), Some(s)) = (
+            fields.next().and_then(|f| f.chars().next()),
+            fields.next().and_then(|f| f.chars().next()),
+        ) else {
+            continue;
+        };
+        table.simplified.insert(t, s);
+        // `*` marks a one way pair
+        if fields.next() != Some("*") {
+            table.traditional.entry(s).or_insert(t);
+        }
+    }
+    table
+});
+
+fn convert(term: &str, map: &HashMap<char, char>) -> String {
+    term.chars()
+        .map(|c| map.get(&c).copied().unwrap_or(c))
+        .collect()
+}
+
+pub fn to_simplified(term: &str) -> String {
+    convert(term, &TABLE.simplified)
+}
+
+pub fn to_traditional(term: &str) -> String {
+    convert(term, &TABLE.traditional)
+}
+
+/// the simplified & traditional spellings of `term`, without `term` itself
+pub fn other_forms(term: &str) -> Vec<String> {
+    let mut forms = Vec::new();
+    for form in [to_simplified(term), to_traditional(term)] {
+        if form != term && !forms.contains(&form) {
+            forms.push(form);
+        }
+    }
+    forms
+}
+
+/// a pinyin reading as its letters & tones, so `xíng`, `xing2` & `XING2` are the same.
+/// neutral tones (unmarked, 5 or 0) aren't kept, ü & v are the same letter
+#[derive(Debug, PartialEq)]
+pub struct Pinyin {
+    letters: String,
+    tones: Vec<u8>,
+}
+
+/// vowels with a tone mark, by tone
+const TONE_MARKS: [(char, [char; 4]); 6] = [
+    ('a', ['ā', 'á', 'ǎ', 'à']),
+    ('e', ['ē', 'é', 'ě', 'è']),
+    ('i', ['ī', 'í', 'ǐ', 'ì']),
+    ('o', ['ō', 'ó', 'ǒ', 'ò']),
+    ('u', ['ū', 'ú', 'ǔ', 'ù']),
+    ('v', ['ǖ', 'ǘ', 'ǚ', 'ǜ']),
+];
+
+/// `None` for anything that isn't pinyin, like kana readings
+pub fn parse_pinyin(reading: &str) -> Option<Pinyin> {
+    let mut pinyin = Pinyin {
+        letters: String::new(),
+        tones: Vec::new(),
+    };
+    for c in reading.chars().flat_map(char::to_lowercase) {
+        match c {
+            'ü' => pinyin.letters.push('v'),
+            'a'..='z' => pinyin.letters.push(c),
+            '1'..='4' => pinyin.tones.push(c as u8 - b'0'),
+            // combining tone marks, for decomposed input
+            '\u{304}' => pinyin.tones.push(1),
+            '\u{301}' => pinyin.tones.push(2),
+            '\u{30c}' => pinyin.tones.push(3),
+            '\u{300}' => pinyin.tones.push(4),
+            '\u{308}' => {
+                // u + combining diaeresis
+                if pinyin.letters.pop() != Some('u') {
+                    return None;
+                }
+                pinyin.letters.push('v');
+            }
+            '0' | '5' | ' ' | '\'' | '-' => {}
+            _ => {
+                let (letter, tone) = TONE_MARKS.iter().find_map(|(letter, marks)| {
+                    let tone = marks.iter().position(|m| *m == c)?;
+                    Some((*letter, tone as u8 + 1))
+                })?;
+                pinyin.letters.push(letter);
+                pinyin.tones.push(tone);
+            }
+        }
+    }
+    (!pinyin.letters.is_empty()).then_some(pinyin)
+}
+
+/// how well a stored reading matches the requested pinyin, lower is better:
+/// same tones, a reading without tones, no pinyin reading at all, another reading
+pub fn pinyin_rank(wanted: &Pinyin, reading: Option<&str>) -> u8 {
+    match reading.and_then(parse_pinyin) {
+        Some(p) if p == *wanted => 0,
+        Some(p)
+            if p.letters == wanted.letters && (p.tones.is_empty() || wanted.tones.is_empty()) =>
+        {
+            1
+        }
+        None => 2,
+        Some(_) => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{other_forms, parse_pinyin, pinyin_rank, to_simplified, to_traditional};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn conversion() {
+        assert_eq!(to_simplified("說話"), "说话");
+        assert_eq!(to_traditional("说话"), "說話");
+        assert_eq!(other_forms("電腦"), ["电脑"]);
+        assert_eq!(other_forms("电脑"), ["電腦"]);
+        // mixed input goes both ways
+        assert_eq!(other_forms("电腦"), ["电脑", "電腦"]);
+        assert!(other_forms("你好").is_empty());
+        // 面 is traditional too, so it's never turned into 麵
+        assert_eq!(to_simplified("麵"), "面");
+        assert_eq!(to_traditional("面"), "面");
+    }
+
+    #[test]
+    fn pinyin() {
+        assert_eq!(parse_pinyin("xíng"), parse_pinyin("xing2"));
+        assert_eq!(parse_pinyin("Nǚ'ér"), parse_pinyin("nv3 er2"));
+        assert_eq!(parse_pinyin("ma1ma5"), parse_pinyin("māma"));
+        // decomposed marks
+        assert_eq!(parse_pinyin("xi\u{301}ng"), parse_pinyin("xing2"));
+        assert_eq!(parse_pinyin("ほん"), None);
+
+        let xing = parse_pinyin("xing2").unwrap();
+        assert_eq!(pinyin_rank(&xing, Some("xíng")), 0);
+        assert_eq!(pinyin_rank(&xing, Some("xing")), 1);
+        assert_eq!(pinyin_rank(&xing, None), 2);
+        assert_eq!(pinyin_rank(&xing, Some("háng")), 3);
+        assert_eq!(pinyin_rank(&xing, Some("xing4")), 3);
+    }
+}
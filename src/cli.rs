@@ -1,12 +1,18 @@
 use std::fmt::Display;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use clap::builder::ValueParserFactory;
-use clap::{Parser as ClapParser, ValueEnum as ClapValueEnum};
+use clap::{
+    Args as ClapArgs, Parser as ClapParser, Subcommand as ClapSubcommand,
+    ValueEnum as ClapValueEnum,
+};
 use serde::Serialize;
 #[allow(unused_imports)]
 use tracing::info;
 
+use crate::database::AudioSource;
+
 #[derive(ClapParser, Debug, Clone)]
 #[non_exhaustive]
 pub struct Cli {
@@ -16,12 +22,131 @@ pub struct Cli {
     /// The port to run the server on. Ex: --port 8080
     #[arg(long, default_value_t = PortType::default())]
     pub port: PortType,
-    /// The path to the audio folder. defaults to the yas executable path
+    /// The path to the audio folder. defaults to the yas executable path.
+    /// can be given multiple times to serve sources from several folders
     #[arg(short, long, default_value = "./audio")]
-    pub audio: PathBuf,
+    pub audio: Vec<PathBuf>,
     /// Prints the available sources. Can be used to sort with "./sort.txt" file
     #[arg(long)]
     pub sources: bool,
+    /// The path to the entries database
+    #[arg(long, default_value = "./entries.db")]
+    pub db: PathBuf,
+    /// The path to your own database of entries, added to (or hiding) the shipped ones.
+    /// created if it doesn't exist
+    #[arg(long, default_value = "./user.db")]
+    pub user_db: PathBuf,
+    /// Loads entries.db into memory at startup for faster lookups (uses more RAM)
+    #[arg(long)]
+    pub memory: bool,
+    /// The folder derived data (audio metadata, processed clips) is cached in
+    #[arg(long, default_value = "./cache")]
+    pub cache: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(ClapSubcommand, Debug, Clone)]
+pub enum CliCommand {
+    /// Probes every audio file for its duration, format & loudness and caches the results
+    Scan {
+        /// Re-probe files that already have cached metadata
+        #[arg(long)]
+        force: bool,
+    },
+    /// Packs a source folder into a single sqlite file (<audio>/<source>.db) served in its place
+    Import {
+        /// The source to pack, ex: forvo_jp
+        #[arg(value_parser = parse_source)]
+        source: String,
+        /// The folder to pack. defaults to <audio>/<source> of the folder that has it
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+    /// Upgrades the entries database to the latest schema (also done at startup)
+    Migrate,
+    /// Pins entries to always be served first for a word, stored in the user database
+    Pin {
+        #[command(subcommand)]
+        command: PinCommand,
+    },
+    /// Blocks speakers or files from ever being served, stored in the user database
+    Block {
+        #[command(subcommand)]
+        command: BlockCommand,
+    },
+}
+
+#[derive(ClapSubcommand, Debug, Clone)]
+pub enum BlockCommand {
+    /// Blocks every entry matching all of the given fields
+    Add(BlockArgs),
+    /// Removes a block, given the same fields it was added with
+    Remove(BlockArgs),
+    /// Lists every block
+    List,
+}
+
+/// at least one of `--speaker` & `--file` is required
+#[derive(ClapArgs, Debug, Clone)]
+pub struct BlockArgs {
+    /// Only block the file or speaker for this expression
+    #[arg(long, default_value = "")]
+    pub expression: String,
+    /// A forvo speaker, ex: strawberrybrown
+    #[arg(long, default_value = "")]
+    pub speaker: String,
+    /// An audio file, ex: 本.mp3
+    #[arg(long, default_value = "")]
+    pub file: String,
+}
+
+#[derive(ClapSubcommand, Debug, Clone)]
+pub enum PinCommand {
+    /// Pins an entry for an expression, replacing its previous pin
+    Add {
+        expression: String,
+        /// Only pin it for this reading. pins it for every reading when left out
+        #[arg(long, default_value = "")]
+        reading: String,
+        /// The source of the entry, ex: forvo_jp
+        #[arg(long, value_parser = parse_source)]
+        source: String,
+        /// The file of the entry, ex: 本.mp3
+        #[arg(long)]
+        file: String,
+        /// Only pin the recording of this speaker
+        #[arg(long)]
+        speaker: Option<String>,
+    },
+    /// Removes the pin of an expression
+    Remove {
+        expression: String,
+        #[arg(long, default_value = "")]
+        reading: String,
+    },
+    /// Lists every pin
+    List,
+}
+
+/// a source name `AudioSource` knows
+pub fn parse_source(s: &str) -> Result<String, String> {
+    match AudioSource::from_str(s) {
+        Ok(AudioSource::Other) | Err(_) => Err(format!("unknown source {s}, see --sources")),
+        Ok(source) => Ok(source.to_string()),
+    }
+}
+
+impl CliCommand {
+    /// the entries database is opened read-only unless the command has to write to it
+    pub fn writes_db(&self) -> bool {
+        match self {
+            Self::Migrate => true,
+            Self::Scan { .. } | Self::Import { .. } | Self::Pin { .. } | Self::Block { .. } => {
+                false
+            }
+        }
+    }
 }
 
 #[derive(ClapValueEnum, Clone, Copy, Debug, Default, PartialEq)]
@@ -1,51 +1,166 @@
 use clap::builder::OsStr;
-use color_print::{cformat, cprintln, cwrite};
+use color_print::{ceprintln, cformat, cprintln, cwrite};
 use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
 use sysinfo::{Pid, Process, System};
 
 use std::{
+    collections::HashMap,
     env::{current_dir, current_exe},
-    ffi::OsString, fs::canonicalize,
+    ffi::OsString,
+    fs::canonicalize,
+    path::{Path, PathBuf},
 };
 // needed for Command's 'creation_flags' method.
 #[cfg(target_os = "windows")]
 use std::os::windows::process::CommandExt;
 
-use crate::{cli::CliLog, PROGRAM_INFO};
+use crate::{
+    cli::CliLog, database::AudioSource, derived::DeriveParams, speakers::SpeakerConfig,
+    user::Precedence, PROGRAM_INFO,
+};
 
 #[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct Config {
     pub exit_minutes: u64,
     pub debug: bool,
+    /// per source options, keyed by source name (see `--sources`)
+    pub sources: HashMap<String, SourceConfig>,
+    /// whether user.db or entries.db wins when both have the same entry
+    pub overlay: Precedence,
+    /// look up the dictionary form of conjugated terms that have no audio
+    pub deinflect: bool,
+    /// split compounds that have no audio into the words they are made of
+    pub compounds: bool,
+    /// which speakers to serve first, or at all
+    pub speakers: SpeakerConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct SourceConfig {
+    /// trims silence quieter than this (dB) from both ends of every clip. ex: -50
+    pub trim: Option<f64>,
+    /// the audio folder containing this source, if it isn't in one of the `--audio` folders
+    pub root: Option<PathBuf>,
+}
+
+/// which audio folder each source is served from
+#[derive(Debug, Default)]
+pub struct AudioRoots {
+    roots: Vec<PathBuf>,
+    by_source: HashMap<AudioSource, PathBuf>,
+}
+
+impl AudioRoots {
+    /// a source belongs to the root set in config.json, or else to the first `--audio`
+    /// folder that has it as a folder, blob database or zip.
+    pub fn new(cli_roots: &[PathBuf], config: &Config) -> Self {
+        let mut roots = cli_roots.to_vec();
+        let mut by_source = HashMap::new();
+        for source in AudioSource::iter() {
+            let name = source.to_string();
+            if let Some(root) = config.source(&source).and_then(|c| c.root.clone()) {
+                if !roots.contains(&root) {
+                    roots.push(root.clone());
+                }
+                by_source.insert(source, root);
+                continue;
+            }
+            let owner = cli_roots.iter().find(|root| {
+                [name.clone(), format!("{name}.db"), format!("{name}.zip")]
+                    .iter()
+                    .any(|p| root.join(p).exists())
+            });
+            if let Some(root) = owner {
+                by_source.insert(source, root.clone());
+            }
+        }
+        Self { roots, by_source }
+    }
+
+    pub fn get(&self, source: &AudioSource) -> &Path {
+        self.by_source
+            .get(source)
+            .or(self.roots.first())
+            .map(PathBuf::as_path)
+            .unwrap_or(Path::new("./audio"))
+    }
+
+    pub fn all(&self) -> &[PathBuf] {
+        &self.roots
+    }
+}
+
+impl Config {
+    pub fn read_config_file() -> Config {
+        let Ok(str) = std::fs::read_to_string("./config.json") else {
+            return Config::default();
+        };
+        match serde_json::from_str(&str) {
+            Ok(config) => {
+                cprintln!("<i><g>+</> config.json loaded</>");
+                config
+            }
+            Err(e) => {
+                ceprintln!("<r>[error]</> config.json is invalid and was ignored: {e}");
+                Config::default()
+            }
+        }
+    }
+
+    pub fn source(&self, source: &AudioSource) -> Option<&SourceConfig> {
+        self.sources.get(&source.to_string())
+    }
+}
+
+impl SourceConfig {
+    /// fills in the params the request didn't set with this source's defaults
+    pub fn apply(&self, params: &DeriveParams) -> DeriveParams {
+        DeriveParams {
+            trim: params.trim.or(self.trim),
+            ..params.clone()
+        }
+    }
 }
 
 #[allow(unused_mut)]
 #[allow(clippy::zombie_processes)]
 pub fn spawn_headless() {
-    let audio_path = &PROGRAM_INFO.get().unwrap().cli.audio;
+    let cli = &PROGRAM_INFO.get().unwrap().cli;
     let exe = &PROGRAM_INFO.get().unwrap().current_exe;
+    let mut args: Vec<OsString> = Vec::new();
+    for audio_path in &cli.audio {
+        args.extend(["--audio".into(), audio_path.into()]);
+    }
+    args.extend([
+        "--db".into(),
+        (&cli.db).into(),
+        "--user-db".into(),
+        (&cli.user_db).into(),
+        "--cache".into(),
+        (&cli.cache).into(),
+        "--port".into(),
+        (&cli.port.inner).into(),
+        "--log".into(),
+        "headless-instance".into(),
+    ]);
+    if cli.memory {
+        args.push("--memory".into());
+    }
 
     #[cfg(target_os = "windows")]
     #[allow(clippy::zombie_processes)]
     let mut handle = std::process::Command::new(exe)
         .creation_flags(0x00000008) // CREATE_NO_WINDOW
-        .args([
-            "--audio",
-            &audio_path.to_string_lossy(),
-            "--log",
-            "headless-instance",
-        ])
+        .args(&args)
         .spawn()
         .unwrap();
     #[cfg(any(target_os = "macos", target_os = "linux"))]
     #[allow(clippy::zombie_processes)]
     let mut handle = std::process::Command::new(exe)
-        .args([
-            "--audio",
-            &audio_path.to_string_lossy(),
-            "--log",
-            "headless-instance",
-        ])
+        .args(&args)
         .stdin(std::process::Stdio::null())
         .stdout(std::process::Stdio::null())
         .stderr(std::process::Stdio::null())
@@ -103,3 +218,38 @@ pub fn kill_previous_instance() {
         proc.kill();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AudioRoots, Config, SourceConfig};
+    use crate::database::AudioSource;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn audio_roots() {
+        let tmp = std::env::temp_dir().join("yas_audio_roots");
+        let (a, b, c) = (tmp.join("a"), tmp.join("b"), tmp.join("c"));
+        std::fs::create_dir_all(a.join("nhk16")).unwrap();
+        std::fs::create_dir_all(b.join("forvo_jp")).unwrap();
+        std::fs::create_dir_all(&c).unwrap();
+        std::fs::write(b.join("jpod.zip"), b"").unwrap();
+
+        let mut config = Config::default();
+        config.sources.insert(
+            "forvo_zh".to_string(),
+            SourceConfig {
+                root: Some(c.clone()),
+                ..Default::default()
+            },
+        );
+        let roots = AudioRoots::new(&[a.clone(), b.clone()], &config);
+        assert_eq!(roots.get(&AudioSource::Nhk16), a);
+        assert_eq!(roots.get(&AudioSource::ForvoJp), b);
+        assert_eq!(roots.get(&AudioSource::Jpod), b);
+        assert_eq!(roots.get(&AudioSource::ForvoZh), c);
+        // unknown sources fall back to the first folder
+        assert_eq!(roots.get(&AudioSource::Daijisen), a);
+        assert_eq!(roots.all(), [a, b, c]);
+        std::fs::remove_dir_all(tmp).unwrap();
+    }
+}
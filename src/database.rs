@@ -1,8 +1,12 @@
-use color_print::cprintln;
+use color_print::{ceprintln, cprintln};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use sqlx::Error as SqlxError;
-use sqlx::{prelude::FromRow, sqlite::SqlitePool};
+use sqlx::{
+    prelude::FromRow,
+    sqlite::{SqliteConnectOptions, SqlitePool},
+};
+use std::collections::HashSet;
 use std::fs::read_dir;
 use std::path::Path;
 use std::path::PathBuf;
@@ -12,7 +16,12 @@ use thiserror::Error;
 use tokio::join;
 
 use crate::PROGRAM_INFO;
-use crate::helper::{AudioFileError, AudioResult, KANA_MAP};
+use crate::derived::DeriveParams;
+use crate::helper::{is_kana, to_hiragana, to_katakana, AudioFileError, AudioResult};
+use crate::search::Paging;
+use crate::user::{self, UserFilters};
+use crate::variants;
+use crate::{chinese, deinflect};
 
 #[derive(Default, Deserialize, Serialize, Debug, FromRow, Clone)]
 pub struct DatabaseEntry {
@@ -22,6 +31,57 @@ pub struct DatabaseEntry {
     pub speaker: Option<String>,
     pub display: String,
     pub file: String,
+    /// offset (ms) of the word inside `file`, for sources recorded as one long file
+    #[sqlx(default)]
+    #[serde(default)]
+    pub start_ms: Option<i64>,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub end_ms: Option<i64>,
+    /// only in the user database: hides the shipped entry with the same expression, source & file
+    #[sqlx(default)]
+    #[serde(default)]
+    pub hidden: bool,
+    /// set when the entry is served for a term it isn't stored under
+    #[sqlx(skip)]
+    #[serde(skip)]
+    pub fallback: Option<Fallback>,
+}
+
+/// why an entry was served for a term it isn't stored under, shown in its name
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fallback {
+    /// another spelling of the term, ex: 引っ越し for 引越し
+    Variant(String),
+    /// the dictionary form of a conjugated term, ex: 食べる for 食べました
+    Deinflected(String),
+    /// one word of a compound that has no audio itself, ex: 日本 for 日本語学校
+    Compound(CompoundPart),
+}
+
+/// where a clip fits in a compound that was split into words it has audio for
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CompoundPart {
+    /// 1 based, `None` for the clip of every part joined together
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub part: Option<usize>,
+    pub parts: usize,
+    pub expression: String,
+}
+
+impl std::fmt::Display for Fallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Variant(expression) => write!(f, "variant {expression}"),
+            Self::Deinflected(expression) => write!(f, "deinflected {expression}"),
+            Self::Compound(CompoundPart {
+                part: Some(part),
+                parts,
+                expression,
+            }) => write!(f, "compound {part}/{parts} {expression}"),
+            Self::Compound(CompoundPart { expression, .. }) => write!(f, "compound {expression}"),
+        }
+    }
 }
 
 impl DatabaseEntry {
@@ -62,13 +122,34 @@ impl DatabaseEntry {
             }
         }
         Err(AudioFileError::MissingAudioFile {
-            entry: self.clone(),
+            entry: Box::new(self.clone()),
             dir: dir.as_ref().display().to_string(),
         })
     }
 
+    fn compound_part(&self) -> Option<CompoundPart> {
+        match &self.fallback {
+            Some(Fallback::Compound(part)) => Some(part.clone()),
+            _ => None,
+        }
+    }
+
+    /// the query asking the audio route to process `file`: the request's params,
+    /// the config of the source and the clip offsets of the entry
+    fn derive_query(&self, derive: &DeriveParams, file: &Path) -> Option<String> {
+        let pi = PROGRAM_INFO.get().unwrap();
+        let mut derive = match pi.config.source(&self.source) {
+            Some(cfg) => cfg.apply(derive),
+            None => derive.clone(),
+        }
+        .for_file(file);
+        derive.start = self.start_ms;
+        derive.end = self.end_ms;
+        (!derive.is_empty()).then(|| derive.to_query())
+    }
+
     // Construct the audio source based on the file path
-    pub fn to_audio_result(&self) -> Result<AudioResult, AudioFileError> {
+    pub fn to_audio_result(&self, derive: &DeriveParams) -> Result<AudioResult, AudioFileError> {
         let pi = PROGRAM_INFO.get().unwrap();
         let DatabaseEntry {
             source,
@@ -77,8 +158,62 @@ impl DatabaseEntry {
             ..
         } = self;
 
+        let mut name = if display.is_empty() {
+            source.to_string()
+        } else {
+            format!("{} {}", source, display)
+        };
+        if let Some(fallback) = &self.fallback {
+            name = format!("{name} ({fallback})");
+        }
+
+        // packed sources are served from their blob database
+        if let Some(pack) = pi.blobs.get(source) {
+            let Some(path) = pack.index.find(file, display) else {
+                return Err(AudioFileError::MissingAudioFile {
+                    entry: Box::new(self.clone()),
+                    dir: format!("{source}.db"),
+                });
+            };
+            let mut url = format!("http://{}/blob/{source}/{path}", pi.cli.port.inner);
+            if let Some(query) = self.derive_query(derive, Path::new(path)) {
+                url = format!("{url}?{query}");
+            }
+            return Ok(AudioResult {
+                name,
+                url,
+                meta: None,
+                path: None,
+                clip: None,
+                compound: self.compound_part(),
+            });
+        }
+
+        // archived sources are served from their zip, under the same /audio/<source> path
+        if let Some(zip) = pi.zips.get(source) {
+            let Some(path) = zip.index.find(file, display) else {
+                return Err(AudioFileError::MissingAudioFile {
+                    entry: Box::new(self.clone()),
+                    dir: format!("{source}.zip"),
+                });
+            };
+            let mut url = format!("http://{}/audio/{source}/{path}", pi.cli.port.inner);
+            if let Some(query) = self.derive_query(derive, Path::new(path)) {
+                url = format!("{url}?{query}");
+            }
+            return Ok(AudioResult {
+                name,
+                url,
+                meta: None,
+                path: None,
+                clip: None,
+                compound: self.compound_part(),
+            });
+        }
+
         // Build the directory using the CLI-supplied audio folder.
-        let read_dir = pi.cli.audio.join(source.to_string());
+        let root = pi.roots.get(source);
+        let read_dir = root.join(source.to_string());
 
         // First try: <cli_audio>/<source>/media/<file>
         let mut file_path = read_dir.join("media").join(file);
@@ -93,22 +228,28 @@ impl DatabaseEntry {
         }
 
         // Compute the relative path from the CLI audio folder so the URL uses the alias.
-        let relative_path = file_path.strip_prefix(&pi.cli.audio).unwrap_or(&file_path);
+        // the path starts with the source, which is how the audio route finds the root again.
+        let relative_path = file_path.strip_prefix(root).unwrap_or(&file_path);
 
         // Build URL using the alias "audio" (as set up in Actix).
-        let url = format!(
+        let mut url = format!(
             "http://{}/audio/{}",
             pi.cli.port.inner,
             relative_path.display()
         );
+        if let Some(query) = self.derive_query(derive, &file_path) {
+            url = format!("{url}?{query}");
+        }
 
-        let name = if display.is_empty() {
-            source.to_string()
-        } else {
-            format!("{} {}", source, display)
-        };
-
-        Ok(AudioResult { name, url })
+        Ok(AudioResult {
+            name,
+            url,
+            meta: None,
+            path: Some(file_path),
+            clip: (self.start_ms.is_some() || self.end_ms.is_some())
+                .then(|| (self.start_ms.unwrap_or(0), self.end_ms)),
+            compound: self.compound_part(),
+        })
     }
 }
 
@@ -118,7 +259,9 @@ pub enum AudioSourceError {
     // UnkownSource { src: String },
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, sqlx::Type, EnumIter)]
+#[derive(
+    Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq, Hash, sqlx::Type, EnumIter,
+)]
 #[sqlx(type_name = "TEXT")]
 #[sqlx(rename_all = "lowercase")]
 pub enum AudioSource {
@@ -161,31 +304,134 @@ impl FromStr for AudioSource {
 }
 
 impl AudioSource {
+    pub fn is_forvo(&self) -> bool {
+        matches!(self, Self::ForvoJp | Self::ForvoZh)
+    }
+
     pub fn display_all_variants() {
         println!("\n[audio sources]");
         for var in AudioSource::iter() {
             println!("{var}");
         }
     }
-    pub fn read_sort_file() -> Vec<AudioSource> {
-        let default = vec![
+}
+
+/// a line of `sort.txt`: a source, or a single speaker of it (`forvo_jp:strawberrybrown`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub source: AudioSource,
+    pub speaker: Option<String>,
+}
+
+impl SortKey {
+    fn matches(&self, e: &DatabaseEntry) -> bool {
+        e.source == self.source && (self.speaker.is_none() || self.speaker == e.speaker)
+    }
+}
+
+/// the order entries are served in, from `sort.txt`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortOrder {
+    pub keys: Vec<SortKey>,
+    /// `-source` & `-source:speaker` lines, never served
+    pub excluded: Vec<SortKey>,
+}
+
+impl SortOrder {
+    pub fn from_sources(sources: &[AudioSource]) -> Self {
+        let keys = sources
+            .iter()
+            .map(|source| SortKey {
+                source: *source,
+                speaker: None,
+            })
+            .collect();
+        Self {
+            keys,
+            excluded: Vec::new(),
+        }
+    }
+
+    /// the line of an entry's speaker, else the line of its source, else after every line
+    pub fn position(&self, e: &DatabaseEntry) -> usize {
+        let speaker = e.speaker.as_ref().and_then(|speaker| {
+            self.keys
+                .iter()
+                .position(|k| k.source == e.source && k.speaker.as_ref() == Some(speaker))
+        });
+        speaker
+            .or_else(|| {
+                self.keys
+                    .iter()
+                    .position(|k| k.source == e.source && k.speaker.is_none())
+            })
+            .unwrap_or(self.keys.len())
+    }
+
+    pub fn is_excluded(&self, e: &DatabaseEntry) -> bool {
+        self.excluded.iter().any(|k| k.matches(e))
+    }
+
+    /// `#` starts a comment. returns a warning (with its line number) for every line that
+    /// isn't understood, those lines are skipped
+    pub fn parse(text: &str) -> (Self, Vec<String>) {
+        let mut order = Self::default();
+        let mut warnings = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (excluded, line) = match line.strip_prefix('-') {
+                Some(line) => (true, line.trim()),
+                None => (false, line),
+            };
+            let (name, speaker) = match line.split_once(':') {
+                Some((name, speaker)) => (name.trim(), Some(speaker.trim().to_string())),
+                None => (line, None),
+            };
+            // everything unknown parses as `other`
+            let Some(source) = AudioSource::from_str(name)
+                .ok()
+                .filter(|s| *s != AudioSource::Other || name == "other")
+            else {
+                warnings.push(format!("line {}: unknown source `{name}`", i + 1));
+                continue;
+            };
+            if speaker.is_some() && !source.is_forvo() {
+                warnings.push(format!("line {}: only forvo sources have speakers", i + 1));
+                continue;
+            }
+            let key = SortKey { source, speaker };
+            match excluded {
+                true => order.excluded.push(key),
+                false => order.keys.push(key),
+            }
+        }
+        (order, warnings)
+    }
+
+    pub fn read_sort_file() -> SortOrder {
+        let default = SortOrder::from_sources(&[
             AudioSource::Daijisen,
             AudioSource::Nhk16,
             AudioSource::Shinmeikai8,
             AudioSource::ForvoJp,
             AudioSource::ForvoZh,
             AudioSource::Jpod,
-        ];
+        ]);
         let Ok(_) = std::fs::File::open("./sort.txt") else {
             return default;
         };
-        let order: Vec<AudioSource> = std::fs::read_to_string("./sort.txt")
-            .expect("failed to read sort.txt. try deleting the file as it may be corrupted")
-            .lines()
-            .flat_map(|str| AudioSource::from_str(str.trim()).ok())
-            .collect();
-        if order.is_empty() {
-            return default;
+        let text = std::fs::read_to_string("./sort.txt")
+            .expect("failed to read sort.txt. try deleting the file as it may be corrupted");
+        let (mut order, warnings) = SortOrder::parse(&text);
+        for warning in &warnings {
+            ceprintln!("<y>[warning]</> sort.txt {warning}. run with <b>--sources</> to see the sources list");
+        }
+        // only exclusions
+        if order.keys.is_empty() {
+            order.keys = default.keys;
         }
         cprintln!("<i><g>+</> sort.txt loaded</>");
         order
@@ -206,6 +452,43 @@ pub enum DbError {
     // MissingEntriesDB,
 }
 
+pub async fn connect(path: &Path, writable: bool) -> Result<SqlitePool, SqlxError> {
+    let opts = SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(!writable);
+    SqlitePool::connect_with(opts).await
+}
+
+/// the entries.db shipped inside the exe, see the `embedded-db` feature
+#[cfg(feature = "embedded-db")]
+static EMBEDDED_DB: &[u8] = include_bytes!("../entries.db");
+
+/// sqlite stores `PRAGMA user_version` as a big endian u32 at offset 60 of the header
+#[cfg(any(feature = "embedded-db", test))]
+fn header_user_version(header: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(header.get(60..64)?.try_into().ok()?))
+}
+
+/// writes the embedded entries.db to `path` if there is no database there yet,
+/// or if the one there has an older schema than the embedded one.
+/// migrations keep the schema version in `user_version` too, so the header is enough to tell.
+#[cfg(feature = "embedded-db")]
+pub fn extract_embedded_db(path: &Path) -> std::io::Result<()> {
+    use std::io::Read;
+
+    if path.exists() {
+        let mut header = [0u8; 100];
+        std::fs::File::open(path)?.read_exact(&mut header)?;
+        if header_user_version(&header) >= header_user_version(EMBEDDED_DB) {
+            return Ok(());
+        }
+        cprintln!("<i><g>+</> {} is outdated, replacing it</>", path.display());
+    }
+    std::fs::write(path, EMBEDDED_DB)?;
+    cprintln!("<i><g>+</> extracted entries.db to {}</>", path.display());
+    Ok(())
+}
+
 async fn query_forvo_base(
     source: &str,
     term: &str,
@@ -222,63 +505,436 @@ async fn query_forvo_base(
     .await
 }
 
-pub async fn query_database(term: &str, reading: &str) -> color_eyre::Result<Vec<DatabaseEntry>> {
-    let pi = PROGRAM_INFO.get().unwrap();
-    let pool = &pi.db;
+/// the dictionary & forvo rows of a single database (shipped or user)
+pub(crate) async fn query_pool(
+    pool: &SqlitePool,
+    term: &str,
+    reading: &str,
+    forvo_source: &str,
+) -> Result<Vec<DatabaseEntry>, sqlx::Error> {
+    // hiragana & katakana readings are the same word.
+    // forvo rows can have a reading too (pinyin), but they're all fetched below
     let fetch_dict_result = sqlx::query_as::<_, DatabaseEntry>(
         "SELECT * FROM entries
-        WHERE expression = ? AND reading = ?",
+        WHERE expression = ? AND reading IN (?, ?) AND source != ?",
     )
     .bind(term)
-    .bind(reading)
+    .bind(to_hiragana(reading))
+    .bind(to_katakana(reading))
+    .bind(forvo_source)
     .fetch_all(pool);
-
-    // decides whether to serve chinese audio or japanese audio.
-    let first_char = reading.chars().next().unwrap();
-    let mut tmp = [0u8; 4];
-    let first = first_char.encode_utf8(&mut tmp);
-    let fetch_forvo_result =
-        if KANA_MAP.get_by_right(first).is_some() || KANA_MAP.get_by_left(first).is_some() {
-            query_forvo_base("forvo_jp", term, pool)
-        } else {
-            query_forvo_base("forvo_zh", term, pool)
-        };
+    let fetch_forvo_result = query_forvo_base(forvo_source, term, pool);
 
     // await them concurrently
     let (result, forvo_result) = join!(fetch_dict_result, fetch_forvo_result);
-    let mut dict_entries = result?;
-    let mut forvo_entries = forvo_result?;
+    let mut entries = result?;
+    entries.extend(forvo_result?);
+    Ok(entries)
+}
 
-    let (de_len, fe_len) = (dict_entries.len(), forvo_entries.len());
+/// the entries of `term` (see `query_entries`), with the entry the user pinned for it first
+pub async fn query_database(term: &str, reading: &str) -> color_eyre::Result<Vec<DatabaseEntry>> {
+    let pi = PROGRAM_INFO.get().unwrap();
+    let filters = pi.user_db.filters(pi.config.speakers.needs_info()).await?;
+    let mut entries = query_entries(term, reading, &filters).await?;
+    if let Some(pin) = pi.user_db.pin_for(term, reading).await? {
+        user::apply_pin(&mut entries, &pin);
+    }
+    Ok(entries)
+}
 
-    /* Handle Results */
-    dict_entries
-        .par_iter_mut()
-        .chain(forvo_entries.par_iter_mut())
-        .for_each(|e| e.strip_folder_name_prefix());
+/// the entries of `term`, falling back to other spellings of it when it has no dictionary audio
+async fn query_entries(
+    term: &str,
+    reading: &str,
+    filters: &UserFilters,
+) -> color_eyre::Result<Vec<DatabaseEntry>> {
+    let mut entries = query_exact(term, reading, filters).await?;
+    if forvo_source(term, reading) == "forvo_zh" {
+        entries.extend(query_chinese_forms(term, reading, filters).await?);
+        if let Some(pinyin) = chinese::parse_pinyin(reading) {
+            rank_by_pinyin(&mut entries, &pinyin, &PROGRAM_INFO.get().unwrap().sort);
+        }
+    }
+    if entries.iter().any(|e| !e.source.is_forvo()) {
+        return Ok(entries);
+    }
+    entries.extend(query_variants(term, reading, filters).await?);
+    let config = &PROGRAM_INFO.get().unwrap().config;
+    if entries.is_empty() && config.deinflect {
+        entries = query_deinflected(term, reading, filters).await?;
+    }
+    if entries.is_empty() && config.compounds {
+        entries = query_compound(term, reading, filters).await?;
+    }
+    Ok(entries)
+}
+
+/// heteronyms like 行 (xíng/háng): recordings with the requested pinyin first, but only among
+/// the ones of the same spelling & `sort.txt` position, the exact spelling still comes first
+fn rank_by_pinyin(entries: &mut [DatabaseEntry], pinyin: &chinese::Pinyin, sort: &SortOrder) {
+    entries.sort_by_key(|e| {
+        let rank = chinese::pinyin_rank(pinyin, e.reading.as_deref());
+        (e.fallback.is_some(), sort.position(e), rank)
+    });
+}
 
-    let mut query_entries: Vec<DatabaseEntry> = Vec::with_capacity(de_len + fe_len);
-    query_entries.extend(dict_entries.into_iter().chain(forvo_entries.into_iter()));
+/// the longest word a compound is split into, in characters
+const MAX_COMPONENT_CHARS: usize = 8;
+
+/// splits a term with no audio into the longest words that have some, left to right.
+/// every word's entries are marked with their position, nothing is returned if the term
+/// can't be split entirely.
+async fn query_compound(
+    term: &str,
+    reading: &str,
+    filters: &UserFilters,
+) -> color_eyre::Result<Vec<DatabaseEntry>> {
+    let chars: Vec<char> = term.chars().collect();
+    let mut candidates = Vec::new();
+    for i in 0..chars.len() {
+        for j in i + 1..=chars.len().min(i + MAX_COMPONENT_CHARS) {
+            if j - i < chars.len() {
+                candidates.push(chars[i..j].iter().collect::<String>());
+            }
+        }
+    }
+    let refs: Vec<&str> = candidates.iter().map(String::as_str).collect();
+    let known = known_expressions(&refs).await?;
+    let Some(words) = split_compound(&chars, &known) else {
+        return Ok(Vec::new());
+    };
 
-    query_entries.par_sort_unstable_by(|a, b| {
-        let order = &pi.sort;
-        let a_index = order
+    let pi = PROGRAM_INFO.get().unwrap();
+    let mut reading_left = to_hiragana(reading);
+    let mut entries = Vec::new();
+    for (i, word) in words.iter().enumerate() {
+        let mut readings = match &pi.memory {
+            Some(memory) => memory.readings(word),
+            None => query_readings(&pi.db, word).await?,
+        };
+        readings.extend(query_readings(pi.user_db.pool(), word).await?);
+        // the reading that continues the compound's, else the first one
+        let word_reading = readings
             .iter()
-            .position(|x| *x == a.source)
-            .unwrap_or(order.len());
-        let b_index = order
+            .find(|r| !r.is_empty() && reading_left.starts_with(&to_hiragana(r)))
+            .or(readings.first())
+            .cloned()
+            .unwrap_or_default();
+        if let Some(rest) = reading_left.strip_prefix(&to_hiragana(&word_reading)) {
+            reading_left = rest.to_string();
+        }
+
+        let fallback = Fallback::Compound(CompoundPart {
+            part: Some(i + 1),
+            parts: words.len(),
+            expression: word.clone(),
+        });
+        for mut e in query_exact(word, &word_reading, filters).await? {
+            e.fallback = Some(fallback.clone());
+            entries.push(e);
+        }
+    }
+    Ok(entries)
+}
+
+/// splits into the longest known words from the left that still leave a splittable rest,
+/// `None` unless the whole term splits into 2+ known words
+fn split_compound(chars: &[char], known: &HashSet<String>) -> Option<Vec<String>> {
+    let n = chars.len();
+    // ends[i]: where the word starting at i ends, if chars[i..] splits into known words
+    let mut ends: Vec<Option<usize>> = vec![None; n + 1];
+    for i in (0..n).rev() {
+        // the whole term is a word, not a compound
+        let max = if i == 0 { n - 1 } else { n };
+        let end = max.min(i + MAX_COMPONENT_CHARS);
+        ends[i] = (i + 1..=end)
+            .rev()
+            .filter(|&j| j == n || ends[j].is_some())
+            .find(|&j| known.contains(&chars[i..j].iter().collect::<String>()));
+    }
+    let mut words = Vec::new();
+    let mut i = 0;
+    while i < n {
+        let j = ends[i]?;
+        words.push(chars[i..j].iter().collect());
+        i = j;
+    }
+    (words.len() > 1).then_some(words)
+}
+
+/// the distinct readings of an expression, in table order
+pub(crate) async fn query_readings(
+    pool: &SqlitePool,
+    expression: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    let readings: Vec<(String,)> = sqlx::query_as(
+        "SELECT reading FROM entries
+        WHERE expression = ? AND reading IS NOT NULL
+        GROUP BY reading
+        ORDER BY MIN(id)",
+    )
+    .bind(expression)
+    .fetch_all(pool)
+    .await?;
+    Ok(readings.into_iter().map(|(r,)| r).collect())
+}
+
+/// the entries of the first dictionary form of a conjugated term (see `deinflect`)
+/// that has dictionary audio with the same reading
+async fn query_deinflected(
+    term: &str,
+    reading: &str,
+    filters: &UserFilters,
+) -> color_eyre::Result<Vec<DatabaseEntry>> {
+    let pairs = deinflect::deinflect_with_reading(term, reading);
+    let mut candidates: Vec<&str> = pairs.iter().map(|(d, _)| d.term.as_str()).collect();
+    candidates.sort_unstable();
+    candidates.dedup();
+    let known = known_expressions(&candidates).await?;
+
+    for (d, reading) in &pairs {
+        if !known.contains(&d.term) {
+            continue;
+        }
+        let entries = query_exact(&d.term, reading, filters).await?;
+        // forvo entries don't have a reading to check the guess against
+        if entries.iter().any(|e| !e.source.is_forvo()) {
+            let fallback = Fallback::Deinflected(d.term.clone());
+            return Ok(entries
+                .into_iter()
+                .map(|mut e| {
+                    e.fallback = Some(fallback.clone());
+                    e
+                })
+                .collect());
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// which of `expressions` the shipped or user database has entries for
+async fn known_expressions(expressions: &[&str]) -> Result<HashSet<String>, sqlx::Error> {
+    async fn query(pool: &SqlitePool, expressions: &[&str]) -> Result<Vec<(String,)>, sqlx::Error> {
+        if expressions.is_empty() {
+            return Ok(Vec::new());
+        }
+        let placeholders = vec!["?"; expressions.len()].join(", ");
+        let sql =
+            format!("SELECT DISTINCT expression FROM entries WHERE expression IN ({placeholders})");
+        let mut query = sqlx::query_as(&sql);
+        for e in expressions {
+            query = query.bind(*e);
+        }
+        query.fetch_all(pool).await
+    }
+
+    let pi = PROGRAM_INFO.get().unwrap();
+    let mut known: HashSet<String> = match &pi.memory {
+        Some(memory) => expressions
             .iter()
-            .position(|x| *x == b.source)
-            .unwrap_or(order.len());
-        a_index.cmp(&b_index)
-    });
+            .filter(|e| memory.contains(e))
+            .map(|e| e.to_string())
+            .collect(),
+        None => query(&pi.db, expressions)
+            .await?
+            .into_iter()
+            .map(|(e,)| e)
+            .collect(),
+    };
+    known.extend(
+        query(pi.user_db.pool(), expressions)
+            .await?
+            .into_iter()
+            .map(|(e,)| e),
+    );
+    Ok(known)
+}
+
+/// dictionary entries of the other spellings of `term` with the same reading (see `variants`)
+async fn query_variants(
+    term: &str,
+    reading: &str,
+    filters: &UserFilters,
+) -> color_eyre::Result<Vec<DatabaseEntry>> {
+    if reading.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    for (expression, stored) in expressions_with_reading(reading).await? {
+        if !variants::is_variant(term, &expression) {
+            continue;
+        }
+        let fallback = Fallback::Variant(expression.clone());
+        for mut e in query_exact(&expression, &stored, filters).await? {
+            if !e.source.is_forvo() {
+                e.fallback = Some(fallback.clone());
+                entries.push(e);
+            }
+        }
+    }
+    Ok(sort_entries(entries))
+}
+
+/// forvo_zh recordings of the simplified or traditional spelling of `term`, after the exact ones
+async fn query_chinese_forms(
+    term: &str,
+    reading: &str,
+    filters: &UserFilters,
+) -> color_eyre::Result<Vec<DatabaseEntry>> {
+    let mut entries = Vec::new();
+    for form in chinese::other_forms(term) {
+        let fallback = Fallback::Variant(form.clone());
+        for mut e in query_exact(&form, reading, filters).await? {
+            if e.source == AudioSource::ForvoZh {
+                e.fallback = Some(fallback.clone());
+                entries.push(e);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// decides whether to serve chinese audio or japanese audio.
+/// an empty reading falls back to the term (kana words)
+fn forvo_source(term: &str, reading: &str) -> &'static str {
+    let first_char = reading.chars().next().or_else(|| term.chars().next());
+    if first_char.is_some_and(is_kana) {
+        "forvo_jp"
+    } else {
+        "forvo_zh"
+    }
+}
+
+/// the entries stored under exactly `term`, without the ones on the user's blocklist
+async fn query_exact(
+    term: &str,
+    reading: &str,
+    filters: &UserFilters,
+) -> color_eyre::Result<Vec<DatabaseEntry>> {
+    let pi = PROGRAM_INFO.get().unwrap();
+    let forvo_source = forvo_source(term, reading);
+
+    let mut user_entries = query_pool(pi.user_db.pool(), term, reading, forvo_source).await?;
+    user_entries
+        .par_iter_mut()
+        .for_each(|e| e.strip_folder_name_prefix());
+    // entries of the memory index are already stripped & sorted when it was loaded
+    let (mut entries, sorted) = match &pi.memory {
+        Some(memory) => {
+            let forvo_source = AudioSource::from_str(forvo_source).unwrap_or_default();
+            let shipped_entries = memory.lookup(term, reading, forvo_source);
+            match user_entries.is_empty() {
+                true => (shipped_entries, true),
+                false => (
+                    user::overlay(shipped_entries, user_entries, pi.config.overlay),
+                    false,
+                ),
+            }
+        }
+        None => {
+            let mut shipped_entries = query_pool(&pi.db, term, reading, forvo_source).await?;
+            shipped_entries
+                .par_iter_mut()
+                .for_each(|e| e.strip_folder_name_prefix());
+            (
+                user::overlay(shipped_entries, user_entries, pi.config.overlay),
+                false,
+            )
+        }
+    };
+    user::remove_blocked(&mut entries, &filters.blocklist);
+    entries.retain(|e| !pi.sort.is_excluded(e));
 
-    Ok(query_entries)
+    let speakers = &pi.config.speakers;
+    if speakers.is_empty() {
+        return Ok(if sorted {
+            entries
+        } else {
+            sort_entries(entries)
+        });
+    }
+    speakers.apply(&mut entries, &filters.speakers);
+    Ok(sort_entries(entries))
+}
+
+/// every expression with the given reading (in hiragana or katakana), with the reading as stored
+async fn query_expressions_by_reading(
+    pool: &SqlitePool,
+    reading: &str,
+) -> Result<Vec<(String, String)>, sqlx::Error> {
+    // MIN(id) has to be a result column for sqlite to take `reading` from the same row
+    let rows: Vec<(String, String, i64)> = sqlx::query_as(
+        "SELECT expression, reading, MIN(id) AS first_id FROM entries
+        WHERE reading IN (?, ?)
+        GROUP BY expression
+        ORDER BY first_id",
+    )
+    .bind(to_hiragana(reading))
+    .bind(to_katakana(reading))
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(expression, reading, _)| (expression, reading))
+        .collect())
+}
+
+/// every expression of the shipped & user database with this reading, with the reading as stored
+async fn expressions_with_reading(reading: &str) -> Result<Vec<(String, String)>, sqlx::Error> {
+    let pi = PROGRAM_INFO.get().unwrap();
+    let mut expressions = match &pi.memory {
+        Some(memory) => memory.expressions_by_reading(reading),
+        None => query_expressions_by_reading(&pi.db, reading).await?,
+    };
+    for pair in query_expressions_by_reading(pi.user_db.pool(), reading).await? {
+        if !expressions.iter().any(|(e, _)| *e == pair.0) {
+            expressions.push(pair);
+        }
+    }
+    Ok(expressions)
+}
+
+/// reverse lookup: a page of the expressions that have audio for `reading`, each with its entries,
+/// and the number of expressions on every page
+pub async fn query_by_reading(
+    reading: &str,
+    paging: Paging,
+) -> color_eyre::Result<(usize, Vec<(String, String, Vec<DatabaseEntry>)>)> {
+    let pi = PROGRAM_INFO.get().unwrap();
+    let filters = pi.user_db.filters(pi.config.speakers.needs_info()).await?;
+    let expressions = expressions_with_reading(reading).await?;
+    let total = expressions.len();
+    let mut groups = Vec::new();
+    for (expression, reading) in expressions
+        .into_iter()
+        .skip(paging.offset())
+        .take(paging.per_page() as usize)
+    {
+        let mut entries = query_exact(&expression, &reading, &filters).await?;
+        // hidden by the user db
+        if entries.is_empty() {
+            continue;
+        }
+        if let Some(pin) = pi.user_db.pin_for(&expression, &reading).await? {
+            user::apply_pin(&mut entries, &pin);
+        }
+        groups.push((expression, reading, entries));
+    }
+    Ok((total, groups))
+}
+
+fn sort_entries(mut query_entries: Vec<DatabaseEntry>) -> Vec<DatabaseEntry> {
+    let pi = PROGRAM_INFO.get().unwrap();
+    // stable, so entries keep their order (user rows first, speakers) within a source
+    query_entries.par_sort_by_key(|e| pi.sort.position(e));
+    query_entries
 }
 
 #[cfg(test)]
 mod db {
     use super::query_database;
+    use crate::derived::DeriveParams;
     use crate::{PROGRAM_INFO, database::DatabaseEntry, helper::AudioResult};
     use pretty_assertions::assert_eq;
     use std::time::Instant;
@@ -322,6 +978,10 @@ mod db {
             speaker: Some("strawberrybrown".to_string()),
             display: "strawberrybrown".to_string(),
             file: "日本語.mp3".to_string(),
+            start_ms: None,
+            end_ms: None,
+            hidden: false,
+            fallback: None,
         };
         let instant = Instant::now();
         e.find_audio_file("F:/Programming/Rust/yomichan_http_server/audio")
@@ -329,6 +989,134 @@ mod db {
         println!("sync_elapsed: {:?}", instant.elapsed());
     }
 
+    #[test]
+    fn pinyin_within_form() {
+        use super::{chinese, rank_by_pinyin, AudioSource, Fallback, SortOrder};
+
+        let entry = |source, reading: &str, variant: Option<&str>| DatabaseEntry {
+            source,
+            reading: Some(reading.to_string()),
+            file: format!("{reading}.mp3"),
+            fallback: variant.map(|v| Fallback::Variant(v.to_string())),
+            ..Default::default()
+        };
+        // both spellings have a recording of each reading
+        let mut entries = vec![
+            entry(AudioSource::ForvoZh, "háng", None),
+            entry(AudioSource::ForvoZh, "xíng", None),
+            entry(AudioSource::ForvoJp, "xíng", None),
+            entry(AudioSource::ForvoZh, "háng", Some("other form")),
+            entry(AudioSource::ForvoZh, "xíng", Some("other form")),
+        ];
+        let sort = SortOrder::from_sources(&[AudioSource::ForvoJp, AudioSource::ForvoZh]);
+        let pinyin = chinese::parse_pinyin("xing2").unwrap();
+        rank_by_pinyin(&mut entries, &pinyin, &sort);
+        let describe: Vec<String> = entries
+            .iter()
+            .map(|e| format!("{} {} {}", e.source, e.file, e.fallback.is_some()))
+            .collect();
+        assert_eq!(
+            describe,
+            [
+                "forvo_jp xíng.mp3 false",
+                "forvo_zh xíng.mp3 false",
+                "forvo_zh háng.mp3 false",
+                "forvo_zh xíng.mp3 true",
+                "forvo_zh háng.mp3 true",
+            ]
+        );
+    }
+
+    #[test]
+    fn sort_file() {
+        use super::{AudioSource, SortKey, SortOrder};
+
+        let (order, warnings) = SortOrder::parse(
+            "# dictionaries first\n\
+            nhk16\n\
+            forvo_jp:strawberrybrown  # the best one\n\
+            daijisen\n\
+            forvo_jp\n\
+            nkh16\n\
+            nhk16:someone\n\
+            - jpod\n\
+            -forvo_jp:mumbler\n",
+        );
+        assert_eq!(
+            warnings,
+            [
+                "line 6: unknown source `nkh16`",
+                "line 7: only forvo sources have speakers"
+            ]
+        );
+        let key = |source, speaker: Option<&str>| SortKey {
+            source,
+            speaker: speaker.map(str::to_string),
+        };
+        assert_eq!(
+            order.excluded,
+            [
+                key(AudioSource::Jpod, None),
+                key(AudioSource::ForvoJp, Some("mumbler"))
+            ]
+        );
+
+        let entry = |source, speaker: Option<&str>| DatabaseEntry {
+            source,
+            speaker: speaker.map(str::to_string),
+            ..Default::default()
+        };
+        let position = |source, speaker| order.position(&entry(source, speaker));
+        assert_eq!(position(AudioSource::Nhk16, None), 0);
+        assert_eq!(position(AudioSource::ForvoJp, Some("strawberrybrown")), 1);
+        assert_eq!(position(AudioSource::ForvoJp, Some("someone")), 3);
+        assert_eq!(position(AudioSource::ForvoZh, Some("someone")), 4);
+        assert!(order.is_excluded(&entry(AudioSource::Jpod, None)));
+        assert!(order.is_excluded(&entry(AudioSource::ForvoJp, Some("mumbler"))));
+        assert!(!order.is_excluded(&entry(AudioSource::ForvoJp, Some("someone"))));
+    }
+
+    #[test]
+    fn split_compounds() {
+        let known = ["日本", "日本語", "学校", "語", "本", "語学", "校舎"]
+            .map(String::from)
+            .into_iter()
+            .collect();
+        let split = |term: &str| {
+            let chars: Vec<char> = term.chars().collect();
+            super::split_compound(&chars, &known)
+        };
+        assert_eq!(split("日本語学校").unwrap(), ["日本語", "学校"]);
+        assert_eq!(split("本日本").unwrap(), ["本", "日本"]);
+        // 日本語 leaves 学校舎, which doesn't split, so the shorter 日本 is used
+        assert_eq!(split("日本語学校舎").unwrap(), ["日本", "語学", "校舎"]);
+        // every character has to be covered, and a known word isn't a compound
+        assert_eq!(split("日本人"), None);
+        assert_eq!(split("学校"), None);
+    }
+
+    #[test]
+    fn forvo_routing() {
+        let query = |term: &str, reading: &str| crate::helper::IndexQuery {
+            term: Some(term.to_string()),
+            reading: Some(reading.to_string()),
+            ..Default::default()
+        };
+        // toneless pinyin stays pinyin and gets chinese recordings
+        let reading = query("人", "ren").lookup_reading().unwrap();
+        assert_eq!(super::forvo_source("人", &reading), "forvo_zh");
+        let reading = query("読む", "yomu").lookup_reading().unwrap();
+        assert_eq!(super::forvo_source("読む", &reading), "forvo_jp");
+    }
+
+    #[test]
+    fn user_version_header() {
+        let mut header = [0u8; 100];
+        header[60..64].copy_from_slice(&7u32.to_be_bytes());
+        assert_eq!(super::header_user_version(&header), Some(7));
+        assert_eq!(super::header_user_version(&header[..50]), None);
+    }
+
     #[test]
     fn index_audio() {
         let start = Instant::now();
@@ -356,7 +1144,8 @@ mod db {
         let entries = query_database(term, reading).await.unwrap();
         assert!(!entries.is_empty());
 
-        let audio_source_list = AudioResult::create_list(entries.as_slice());
+        let audio_source_list =
+            AudioResult::create_list(entries.as_slice(), &DeriveParams::default());
         AudioResult::print_list(&audio_source_list);
 
         tracing::info!("\nelapsed: {:.3}ms\n", instant.elapsed().as_millis());
@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+// word classes a (de)inflected term can be. 0 is the raw input, which can be anything
+pub const V1: u8 = 1;
+pub const V5: u8 = 1 << 1;
+pub const VK: u8 = 1 << 2;
+pub const VS: u8 = 1 << 3;
+pub const ADJ_I: u8 = 1 << 4;
+/// the て form, which more auxiliaries attach to
+pub const TE: u8 = 1 << 5;
+/// the noun of a する verb, 勉強 for 勉強する
+pub const NOUN: u8 = 1 << 6;
+
+/// deinflection is applied at most this many times, 食べさせられませんでした is 3
+const MAX_DEPTH: usize = 4;
+
+/// replaces the suffix `from` with `to`. applies to the raw input, or to a term
+/// whose class is one of `input`. the result is of class `output`.
+#[derive(Debug)]
+struct Rule {
+    from: String,
+    to: String,
+    input: u8,
+    output: u8,
+    name: &'static str,
+}
+
+/// how each verb class conjugates, by the ending that replaces its dictionary form ending
+struct Conjugation {
+    dict: &'static str,
+    class: u8,
+    /// stem of ない
+    negative: &'static str,
+    /// stem of ます & たい
+    masu: &'static str,
+    /// stem of ば
+    conditional: &'static str,
+    volitional: &'static str,
+    te: &'static str,
+    passive: &'static str,
+    causative: &'static str,
+    potential: &'static str,
+    imperative: &'static str,
+}
+
+#[rustfmt::skip]
+const CONJUGATIONS: &[Conjugation] = &[
+    Conjugation { dict: "う", class: V5, negative: "わ", masu: "い", conditional: "え", volitional: "おう", te: "って", passive: "われる", causative: "わせる", potential: "える", imperative: "え" },
+    Conjugation { dict: "く", class: V5, negative: "か", masu: "き", conditional: "け", volitional: "こう", te: "いて", passive: "かれる", causative: "かせる", potential: "ける", imperative: "け" },
+    Conjugation { dict: "ぐ", class: V5, negative: "が", masu: "ぎ", conditional: "げ", volitional: "ごう", te: "いで", passive: "がれる", causative: "がせる", potential: "げる", imperative: "げ" },
+    Conjugation { dict: "す", class: V5, negative: "さ", masu: "し", conditional: "せ", volitional: "そう", te: "して", passive: "される", causative: "させる", potential: "せる", imperative: "せ" },
+    Conjugation { dict: "つ", class: V5, negative: "た", masu: "ち", conditional: "て", volitional: "とう", te: "って", passive: "たれる", causative: "たせる", potential: "てる", imperative: "て" },
+    Conjugation { dict: "ぬ", class: V5, negative: "な", masu: "に", conditional: "ね", volitional: "のう", te: "んで", passive: "なれる", causative: "なせる", potential: "ねる", imperative: "ね" },
+    Conjugation { dict: "ぶ", class: V5, negative: "ば", masu: "び", conditional: "べ", volitional: "ぼう", te: "んで", passive: "ばれる", causative: "ばせる", potential: "べる", imperative: "べ" },
+    Conjugation { dict: "む", class: V5, negative: "ま", masu: "み", conditional: "め", volitional: "もう", te: "んで", passive: "まれる", causative: "ませる", potential: "める", imperative: "め" },
+    Conjugation { dict: "る", class: V5, negative: "ら", masu: "り", conditional: "れ", volitional: "ろう", te: "って", passive: "られる", causative: "らせる", potential: "れる", imperative: "れ" },
+    // 行く is the one godan く verb with って
+    Conjugation { dict: "行く", class: V5, negative: "行か", masu: "行き", conditional: "行け", volitional: "行こう", te: "行って", passive: "行かれる", causative: "行かせる", potential: "行ける", imperative: "行け" },
+    Conjugation { dict: "いく", class: V5, negative: "いか", masu: "いき", conditional: "いけ", volitional: "いこう", te: "いって", passive: "いかれる", causative: "いかせる", potential: "いける", imperative: "いけ" },
+    Conjugation { dict: "る", class: V1, negative: "", masu: "", conditional: "れ", volitional: "よう", te: "て", passive: "られる", causative: "させる", potential: "られる", imperative: "ろ" },
+    Conjugation { dict: "くる", class: VK, negative: "こ", masu: "き", conditional: "くれ", volitional: "こよう", te: "きて", passive: "こられる", causative: "こさせる", potential: "こられる", imperative: "こい" },
+    Conjugation { dict: "来る", class: VK, negative: "来", masu: "来", conditional: "来れ", volitional: "来よう", te: "来て", passive: "来られる", causative: "来させる", potential: "来られる", imperative: "来い" },
+    Conjugation { dict: "する", class: VS, negative: "し", masu: "し", conditional: "すれ", volitional: "しよう", te: "して", passive: "される", causative: "させる", potential: "できる", imperative: "しろ" },
+];
+
+/// て -> た, で -> だ
+fn past(te: &str) -> String {
+    let stem = &te[..te.len() - 'て'.len_utf8()];
+    match te.ends_with('で') {
+        true => format!("{stem}だ"),
+        false => format!("{stem}た"),
+    }
+}
+
+static RULES: LazyLock<Vec<Rule>> = LazyLock::new(|| {
+    let mut rules = Vec::new();
+    let mut rule = |from: String, to: &str, input: u8, output: u8, name: &'static str| {
+        rules.push(Rule {
+            from,
+            to: to.to_string(),
+            input,
+            output,
+            name,
+        });
+    };
+
+    for c in CONJUGATIONS {
+        let (dict, class) = (c.dict, c.class);
+        for (suffix, name) in [
+            ("ます", "polite"),
+            ("ました", "polite past"),
+            ("ません", "polite negative"),
+            ("ませんでした", "polite past negative"),
+            ("ましょう", "polite volitional"),
+            ("ながら", "while"),
+            ("なさい", "imperative"),
+        ] {
+            rule(format!("{}{suffix}", c.masu), dict, 0, class, name);
+        }
+        // these conjugate like adjectives themselves
+        rule(format!("{}たい", c.masu), dict, ADJ_I, class, "want");
+        rule(
+            format!("{}ない", c.negative),
+            dict,
+            ADJ_I,
+            class,
+            "negative",
+        );
+        rule(format!("{}ず", c.negative), dict, 0, class, "negative");
+        rule(
+            format!("{}ば", c.conditional),
+            dict,
+            0,
+            class,
+            "conditional",
+        );
+        rule(c.volitional.to_string(), dict, 0, class, "volitional");
+        rule(c.imperative.to_string(), dict, 0, class, "imperative");
+        rule(c.te.to_string(), dict, TE, class, "te");
+        let past = past(c.te);
+        rule(format!("{past}ら"), dict, 0, class, "conditional");
+        rule(format!("{past}り"), dict, 0, class, "tari");
+        rule(past, dict, 0, class, "past");
+        // these are ichidan verbs themselves
+        rule(c.passive.to_string(), dict, V1, class, "passive");
+        rule(c.causative.to_string(), dict, V1, class, "causative");
+        rule(c.potential.to_string(), dict, V1, class, "potential");
+    }
+
+    for te in ["て", "で"] {
+        rule(format!("{te}いる"), te, V1, TE, "progressive");
+        rule(format!("{te}る"), te, V1, TE, "progressive");
+        rule(format!("{te}しまう"), te, V5, TE, "completed");
+    }
+
+    rule("かった".to_string(), "い", 0, ADJ_I, "past");
+    rule("くない".to_string(), "い", ADJ_I, ADJ_I, "negative");
+    rule("くて".to_string(), "い", 0, ADJ_I, "te");
+    rule("く".to_string(), "い", 0, ADJ_I, "adverb");
+    rule("ければ".to_string(), "い", 0, ADJ_I, "conditional");
+    rule("かろう".to_string(), "い", 0, ADJ_I, "volitional");
+    rule("する".to_string(), "", VS, NOUN, "suru");
+    // longest suffix first, so 食べました is 食べる before it is 食べます
+    rules.sort_by_key(|r| std::cmp::Reverse(r.from.chars().count()));
+    rules
+});
+
+/// a possible dictionary form of the input
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deinflection {
+    pub term: String,
+    /// the class of `term`
+    pub class: u8,
+    /// the rules that were undone, outermost first
+    pub reasons: Vec<&'static str>,
+}
+
+/// every possible dictionary form of `term`, fewest rules & longest suffixes first.
+/// most of them aren't real words, so they have to be looked up to be of any use.
+pub fn deinflect(term: &str) -> Vec<Deinflection> {
+    let mut seen = HashSet::new();
+    let mut results = Vec::new();
+    let mut current = vec![Deinflection {
+        term: term.to_string(),
+        class: 0,
+        reasons: Vec::new(),
+    }];
+    for _ in 0..MAX_DEPTH {
+        let mut next = Vec::new();
+        for d in &current {
+            for rule in RULES.iter() {
+                if !d.term.ends_with(&rule.from) || (d.class != 0 && d.class & rule.input == 0) {
+                    continue;
+                }
+                let stem = &d.term[..d.term.len() - rule.from.len()];
+                if stem.is_empty() && rule.to.is_empty() {
+                    continue;
+                }
+                let term = format!("{stem}{}", rule.to);
+                if !seen.insert((term.clone(), rule.output)) {
+                    continue;
+                }
+                let mut reasons = d.reasons.clone();
+                reasons.push(rule.name);
+                next.push(Deinflection {
+                    term,
+                    class: rule.output,
+                    reasons,
+                });
+            }
+        }
+        results.extend(next.iter().cloned());
+        current = next;
+    }
+    // the て form is never a dictionary form
+    results.retain(|d| d.class != TE);
+    results
+}
+
+/// deinflects a term and its reading together, pairing forms that undid the same rules.
+/// kana words only have to be deinflected once.
+pub fn deinflect_with_reading(term: &str, reading: &str) -> Vec<(Deinflection, String)> {
+    let terms = deinflect(term);
+    if reading.is_empty() || reading == term {
+        return terms
+            .into_iter()
+            .map(|d| {
+                let reading = d.term.clone();
+                (d, reading)
+            })
+            .collect();
+    }
+    let readings = deinflect(reading);
+    let mut pairs = Vec::new();
+    for d in terms {
+        for r in &readings {
+            if r.class == d.class && r.reasons == d.reasons {
+                pairs.push((d.clone(), r.term.clone()));
+            }
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deinflect, deinflect_with_reading, ADJ_I, NOUN, V1, V5, VK};
+    use pretty_assertions::assert_eq;
+
+    fn finds(input: &str, term: &str, class: u8) -> bool {
+        deinflect(input)
+            .iter()
+            .any(|d| d.term == term && d.class == class)
+    }
+
+    #[test]
+    fn verbs_and_adjectives() {
+        assert!(finds("食べました", "食べる", V1));
+        assert!(finds("書いた", "書く", V5));
+        assert!(finds("行った", "行く", V5));
+        assert!(finds("読んでいる", "読む", V5));
+        assert!(finds("食べさせられませんでした", "食べる", V1));
+        assert!(finds("来なかった", "来る", VK));
+        assert!(finds("高かった", "高い", ADJ_I));
+        assert!(finds("高くなかった", "高い", ADJ_I));
+        assert!(finds("食べたかった", "食べる", V1));
+        assert!(finds("勉強しました", "勉強", NOUN));
+        // only the rules of the class that actually conjugates this way
+        assert!(finds("食べて", "食べる", V1));
+        assert!(!finds("食べて", "食べる", V5));
+        assert!(!finds("見た", "見る", V5));
+        assert!(!finds("書いた", "書く", V1));
+    }
+
+    #[test]
+    fn with_reading() {
+        let pairs = deinflect_with_reading("食べました", "たべました");
+        let first = &pairs[0];
+        assert_eq!(
+            (
+                first.0.term.as_str(),
+                first.1.as_str(),
+                first.0.reasons.clone()
+            ),
+            ("食べる", "たべる", vec!["polite past"])
+        );
+        // pairs are only a guess, the lookup checks the reading
+        assert!(deinflect_with_reading("行った", "いった")
+            .iter()
+            .any(|(d, r)| d.term == "行く" && r == "いく"));
+        let kana = deinflect_with_reading("たべた", "");
+        assert!(kana
+            .iter()
+            .any(|(d, r)| d.term == "たべる" && r == "たべる"));
+    }
+}
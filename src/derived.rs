@@ -0,0 +1,524 @@
+use std::fs::{create_dir_all, rename};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// processing applied to an audio file before it is served.
+/// an empty set of params serves the original file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct DeriveParams {
+    /// cuts the clip out of a longer recording, starting at this offset (ms)
+    pub start: Option<i64>,
+    /// end offset (ms) of the cut
+    pub end: Option<i64>,
+    /// target integrated loudness in LUFS, ex: -16
+    pub normalize: Option<f64>,
+    /// removes leading & trailing silence quieter than this (dB), ex: -50
+    pub trim: Option<f64>,
+    /// time-stretches the clip without changing its pitch, ex: 0.75
+    pub speed: Option<f64>,
+    /// converts the file to this format
+    #[cfg(feature = "transcode")]
+    pub format: Option<AudioFormat>,
+}
+
+#[cfg(feature = "transcode")]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    Mp3,
+    Aac,
+    Ogg,
+    Opus,
+    Flac,
+    Wav,
+    /// mp3, unless the file already plays on iOS
+    Ios,
+}
+
+#[cfg(feature = "transcode")]
+impl AudioFormat {
+    /// extensions iOS plays natively
+    const IOS_SAFE: [&'static str; 5] = ["mp3", "m4a", "aac", "mp4", "wav"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mp3 => "mp3",
+            Self::Aac => "aac",
+            Self::Ogg => "ogg",
+            Self::Opus => "opus",
+            Self::Flac => "flac",
+            Self::Wav => "wav",
+            Self::Ios => "ios",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Aac => "m4a",
+            Self::Ios => "mp3",
+            _ => self.name(),
+        }
+    }
+
+    fn codec_args(&self) -> &'static [&'static str] {
+        match self {
+            Self::Mp3 | Self::Ios => &["-c:a", "libmp3lame", "-q:a", "2"],
+            Self::Aac => &["-c:a", "aac", "-b:a", "128k"],
+            Self::Ogg => &["-c:a", "libvorbis", "-q:a", "5"],
+            Self::Opus => &["-c:a", "libopus", "-b:a", "64k"],
+            Self::Flac => &["-c:a", "flac"],
+            Self::Wav => &["-c:a", "pcm_s16le"],
+        }
+    }
+
+    /// the format a file with this extension has to be converted to, if any
+    fn target_for(self, ext: &str) -> Option<AudioFormat> {
+        let ext = ext.to_lowercase();
+        match self {
+            Self::Ios if Self::IOS_SAFE.contains(&ext.as_str()) => None,
+            Self::Ios => Some(Self::Mp3),
+            f if f.extension() == ext => None,
+            f => Some(f),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeriveError {
+    #[error("failed to run ffmpeg (is it installed and on PATH?): {0}")]
+    Spawn(std::io::Error),
+    #[error("ffmpeg failed on {path}: {stderr}")]
+    Ffmpeg { path: String, stderr: String },
+    #[error("invalid parameter: {0}")]
+    InvalidParam(String),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl actix_web::ResponseError for DeriveError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            Self::InvalidParam(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            Self::Io(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                actix_web::http::StatusCode::NOT_FOUND
+            }
+            _ => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl DeriveParams {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> Result<(), DeriveError> {
+        if self.start.is_some_and(|s| s < 0)
+            || self.end.is_some_and(|e| e <= self.start.unwrap_or(0))
+        {
+            return Err(DeriveError::InvalidParam(format!(
+                "invalid clip offsets: start={:?}ms end={:?}ms",
+                self.start, self.end
+            )));
+        }
+        if let Some(lufs) = self.normalize {
+            if !(-70.0..=-5.0).contains(&lufs) {
+                return Err(DeriveError::InvalidParam(format!(
+                    "normalize must be between -70 and -5 LUFS, got {lufs}"
+                )));
+            }
+        }
+        if let Some(db) = self.trim {
+            if !(-100.0..=0.0).contains(&db) {
+                return Err(DeriveError::InvalidParam(format!(
+                    "trim must be between -100 and 0 dB, got {db}"
+                )));
+            }
+        }
+        if let Some(speed) = self.speed {
+            if !(0.5..=2.0).contains(&speed) {
+                return Err(DeriveError::InvalidParam(format!(
+                    "speed must be between 0.5 and 2, got {speed}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// the query string appended to audio urls so the audio route applies the same params
+    pub fn to_query(&self) -> String {
+        let mut q = Vec::new();
+        if let Some(start) = self.start {
+            q.push(format!("start={start}"));
+        }
+        if let Some(end) = self.end {
+            q.push(format!("end={end}"));
+        }
+        if let Some(lufs) = self.normalize {
+            q.push(format!("normalize={lufs}"));
+        }
+        if let Some(db) = self.trim {
+            q.push(format!("trim={db}"));
+        }
+        if let Some(speed) = self.speed {
+            q.push(format!("speed={speed}"));
+        }
+        #[cfg(feature = "transcode")]
+        if let Some(format) = self.format {
+            q.push(format!("format={}", format.name()));
+        }
+        q.join("&")
+    }
+
+    /// resolves the params against the file they are applied to,
+    /// dropping a format conversion the file doesn't need.
+    pub fn for_file(&self, file: &Path) -> DeriveParams {
+        #[allow(unused_mut)]
+        let mut params = self.clone();
+        #[cfg(feature = "transcode")]
+        {
+            let ext = file.extension().unwrap_or_default().to_string_lossy();
+            params.format = self.format.and_then(|f| f.target_for(&ext));
+        }
+        #[cfg(not(feature = "transcode"))]
+        let _ = file;
+        params
+    }
+
+    /// part of the cache file name, unique per set of params
+    fn cache_key(&self) -> String {
+        let mut key = Vec::new();
+        if self.start.is_some() || self.end.is_some() {
+            let (start, end) = (self.start.unwrap_or(0), self.end.unwrap_or(-1));
+            key.push(format!("c{start}-{end}"));
+        }
+        if let Some(lufs) = self.normalize {
+            key.push(format!("n{lufs}"));
+        }
+        if let Some(db) = self.trim {
+            key.push(format!("t{db}"));
+        }
+        if let Some(speed) = self.speed {
+            key.push(format!("s{speed}"));
+        }
+        #[cfg(feature = "transcode")]
+        if let Some(format) = self.format {
+            key.push(format!("f{}", format.name()));
+        }
+        key.join("_")
+    }
+
+    /// the ffmpeg audio filter chain
+    fn filters(&self) -> Vec<String> {
+        let mut filters = Vec::new();
+        if self.start.is_some() || self.end.is_some() {
+            let mut atrim = format!("atrim=start={}", self.start.unwrap_or(0) as f64 / 1000.0);
+            if let Some(end) = self.end {
+                atrim.push_str(&format!(":end={}", end as f64 / 1000.0));
+            }
+            // the cut has to start at 0 for the filters after it
+            filters.extend([atrim, "asetpts=PTS-STARTPTS".into()]);
+        }
+        if let Some(db) = self.trim {
+            // silenceremove only trims the start reliably, so trim, reverse, trim & reverse back.
+            // keeps 50ms of lead in so the first consonant isn't clipped.
+            let trim =
+                format!("silenceremove=start_periods=1:start_threshold={db}dB:start_silence=0.05");
+            filters.extend([trim.clone(), "areverse".into(), trim, "areverse".into()]);
+        }
+        if let Some(speed) = self.speed.filter(|s| *s != 1.0) {
+            // atempo keeps the pitch
+            filters.push(format!("atempo={speed}"));
+        }
+        if let Some(lufs) = self.normalize {
+            filters.push(format!("loudnorm=I={lufs}:TP=-1.5:LRA=11"));
+        }
+        filters
+    }
+}
+
+/// joins a url path onto a root, refusing anything that could escape it (`..`, absolute paths).
+pub fn safe_join(root: &Path, rel: &str) -> Option<PathBuf> {
+    let rel = Path::new(rel);
+    if rel
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return None;
+    }
+    Some(root.join(rel))
+}
+
+/// the cache key of some content. sha256, so it stays the same across toolchains & platforms
+fn hash_bytes(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes)[..16])
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// reads the whole file, see `MetaCache::file_hash` for the cached version
+pub(crate) fn hash_file(path: &Path) -> std::io::Result<String> {
+    Ok(hash_bytes(&std::fs::read(path)?))
+}
+
+/// returns the processed version of `src`, running ffmpeg only if it isn't cached yet.
+/// cached files are keyed by `hash`, the content hash of the source file, and the params.
+pub fn derive(
+    src: &Path,
+    hash: &str,
+    params: &DeriveParams,
+    cache_dir: &Path,
+) -> Result<PathBuf, DeriveError> {
+    params.validate()?;
+    let params = params.for_file(src);
+    if params.is_empty() {
+        return Ok(src.to_path_buf());
+    }
+    #[allow(unused_mut)]
+    let mut ext = src
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_else(|| "mp3".to_string());
+    #[cfg(feature = "transcode")]
+    if let Some(format) = params.format {
+        ext = format.extension().to_string();
+    }
+    let dir = cache_dir.join("derived");
+    let out = dir.join(format!("{hash}_{}.{ext}", params.cache_key()));
+    if out.exists() {
+        return Ok(out);
+    }
+    create_dir_all(&dir)?;
+
+    let tmp = temp_path(&dir, &format!("{hash}_{}", params.cache_key()), &ext);
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args(["-hide_banner", "-nostats", "-y", "-i"]).arg(src);
+    let filters = params.filters();
+    if !filters.is_empty() {
+        cmd.args(["-af", &filters.join(",")]);
+    }
+    if params.normalize.is_some() {
+        // loudnorm resamples to 192kHz internally
+        cmd.args(["-ar", "44100"]);
+    }
+    #[cfg(feature = "transcode")]
+    if let Some(format) = params.format {
+        // drop cover art & other streams so every container accepts the output
+        cmd.arg("-vn").args(format.codec_args());
+    }
+    run_ffmpeg(cmd, &src.display().to_string(), &tmp, &out)?;
+    Ok(out)
+}
+
+/// `derive` for a file that's only in memory, like the ones of packed sources.
+/// ffmpeg needs a file it can seek in, so the data is written to the cache once
+pub fn derive_bytes(
+    name: &str,
+    data: &[u8],
+    params: &DeriveParams,
+    cache_dir: &Path,
+) -> Result<PathBuf, DeriveError> {
+    params.validate()?;
+    let ext = Path::new(name)
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_else(|| "mp3".to_string());
+    let hash = hash_bytes(data);
+    let dir = cache_dir.join("unpacked");
+    let src = dir.join(format!("{hash}.{ext}"));
+    if !src.exists() {
+        create_dir_all(&dir)?;
+        let tmp = temp_path(&dir, &hash, &ext);
+        std::fs::write(&tmp, data)?;
+        rename(&tmp, &src)?;
+    }
+    derive(&src, &hash, params, cache_dir)
+}
+
+/// write to a temp file first so concurrent requests never serve a half written file
+fn temp_path(dir: &Path, name: &str, ext: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    dir.join(format!("{name}.{nanos}.part.{ext}"))
+}
+
+/// runs `cmd` writing to `tmp`, then moves it to `out`
+fn run_ffmpeg(mut cmd: Command, label: &str, tmp: &Path, out: &Path) -> Result<(), DeriveError> {
+    let output = cmd.arg(tmp).output().map_err(DeriveError::Spawn)?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(tmp);
+        return Err(DeriveError::Ffmpeg {
+            path: label.to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    rename(tmp, out)?;
+    Ok(())
+}
+
+/// joins `parts` (each with its content hash) into a single clip, in the format of the first one.
+/// cached like `derive`, returns the clip and its hash
+pub fn concat(
+    parts: &[(PathBuf, String)],
+    cache_dir: &Path,
+) -> Result<(PathBuf, String), DeriveError> {
+    if parts.len() < 2 {
+        return Err(DeriveError::InvalidParam(
+            "concat needs at least 2 parts".to_string(),
+        ));
+    }
+    let ext = parts[0]
+        .0
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_else(|| "mp3".to_string());
+    let mut hasher = Sha256::new();
+    for (_, hash) in parts {
+        hasher.update(hash.as_bytes());
+    }
+    let name = format!("concat_{}", hex(&hasher.finalize()[..16]));
+    let dir = cache_dir.join("derived");
+    let out = dir.join(format!("{name}.{ext}"));
+    if out.exists() {
+        return Ok((out, name));
+    }
+    create_dir_all(&dir)?;
+
+    let tmp = temp_path(&dir, &name, &ext);
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args(["-hide_banner", "-nostats", "-y"]);
+    for (part, _) in parts {
+        cmd.arg("-i").arg(part);
+    }
+    let inputs: String = (0..parts.len()).map(|i| format!("[{i}:a]")).collect();
+    cmd.args([
+        "-filter_complex",
+        &format!("{inputs}concat=n={}:v=0:a=1", parts.len()),
+        "-vn",
+    ]);
+    let label = parts
+        .iter()
+        .map(|(p, _)| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" + ");
+    run_ffmpeg(cmd, &label, &tmp, &out)?;
+    Ok((out, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_bytes, safe_join, DeriveParams};
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    #[test]
+    fn params() {
+        let empty = DeriveParams::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.to_query(), "");
+
+        let p = DeriveParams {
+            normalize: Some(-16.0),
+            ..Default::default()
+        };
+        assert!(!p.is_empty());
+        assert_eq!(p.to_query(), "normalize=-16");
+        assert_eq!(p.filters(), vec!["loudnorm=I=-16:TP=-1.5:LRA=11"]);
+        assert!(DeriveParams {
+            normalize: Some(3.0),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+
+        let p = DeriveParams {
+            trim: Some(-50.0),
+            speed: Some(0.75),
+            ..p
+        };
+        assert_eq!(p.to_query(), "normalize=-16&trim=-50&speed=0.75");
+        assert_eq!(p.cache_key(), "n-16_t-50_s0.75");
+        assert!(p.filters().contains(&"atempo=0.75".to_string()));
+        // trimming happens before loudness is measured
+        assert_eq!(p.filters()[1], "areverse");
+        assert_eq!(p.filters().last().unwrap(), "loudnorm=I=-16:TP=-1.5:LRA=11");
+    }
+
+    #[test]
+    fn clip_offsets() {
+        let p = DeriveParams {
+            start: Some(12_300),
+            end: Some(13_050),
+            trim: Some(-50.0),
+            ..Default::default()
+        };
+        assert_eq!(p.to_query(), "start=12300&end=13050&trim=-50");
+        assert_eq!(p.cache_key(), "c12300-13050_t-50");
+        // the segment is cut before anything else touches it
+        assert_eq!(p.filters()[0], "atrim=start=12.3:end=13.05");
+        assert_eq!(p.filters()[1], "asetpts=PTS-STARTPTS");
+
+        let bad = |start, end| DeriveParams {
+            start,
+            end,
+            ..Default::default()
+        };
+        assert!(bad(Some(500), Some(500)).validate().is_err());
+        assert!(bad(Some(-1), None).validate().is_err());
+        assert!(bad(None, Some(800)).validate().is_ok());
+    }
+
+    #[cfg(feature = "transcode")]
+    #[test]
+    fn transcode_targets() {
+        use super::AudioFormat;
+
+        let ios = DeriveParams {
+            format: Some(AudioFormat::Ios),
+            ..Default::default()
+        };
+        assert!(ios.for_file(Path::new("a.mp3")).is_empty());
+        assert!(ios.for_file(Path::new("a.M4A")).is_empty());
+        assert_eq!(
+            ios.for_file(Path::new("a.ogg")).format,
+            Some(AudioFormat::Mp3)
+        );
+        assert_eq!(ios.for_file(Path::new("a.ogg")).to_query(), "format=mp3");
+
+        let aac = DeriveParams {
+            format: Some(AudioFormat::Aac),
+            ..Default::default()
+        };
+        assert!(aac.for_file(Path::new("a.m4a")).is_empty());
+        assert_eq!(
+            aac.for_file(Path::new("a.flac")).format,
+            Some(AudioFormat::Aac)
+        );
+    }
+
+    #[test]
+    fn stable_cache_key() {
+        // cached files are named after it, it must not change between builds
+        assert_eq!(hash_bytes(b"hon"), "0c11c4053dbf3c589550b1cde7fecf1f");
+    }
+
+    #[test]
+    fn join_rejects_escapes() {
+        let root = Path::new("audio");
+        assert_eq!(
+            safe_join(root, "nhk16/media/a.mp3"),
+            Some(root.join("nhk16/media/a.mp3"))
+        );
+        assert_eq!(safe_join(root, "../entries.db"), None);
+        assert_eq!(safe_join(root, "nhk16/../../x"), None);
+        assert_eq!(safe_join(root, "/etc/passwd"), None);
+    }
+}
@@ -1,7 +1,15 @@
-use crate::database::DatabaseEntry;
+use crate::database::{CompoundPart, DatabaseEntry};
+use crate::derived::DeriveParams;
+use crate::metadata::AudioMeta;
+use crate::search::Paging;
+use actix_files::HttpRange;
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
 use bimap::BiHashMap;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::LazyLock;
 
 #[derive(thiserror::Error)]
@@ -13,7 +21,11 @@ pub enum AudioFileError {
     // #[error("missing entry speaker. forvo audio must contain a speaker: {forvo_speakers}")]
     // MissingForvoEntrySpeaker { forvo_speakers: String },
     #[error("{dir} does not contain audio file for: {entry:#?}")]
-    MissingAudioFile { entry: DatabaseEntry, dir: String },
+    MissingAudioFile {
+        /// boxed, the entry grew past clippy's `large_enum_variant` limit
+        entry: Box<DatabaseEntry>,
+        dir: String,
+    },
     #[error("io error: {}", .0)]
     Io(#[from] std::io::Error),
 }
@@ -28,15 +40,119 @@ impl std::fmt::Debug for AudioFileError {
 pub struct AudioResult {
     pub name: String,
     pub url: String,
+    #[serde(flatten)]
+    pub meta: Option<AudioMeta>,
+    /// local file backing the url, if any
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// the `start_ms` & `end_ms` cut of `path` the url serves, for entries in a long recording
+    #[serde(skip)]
+    pub clip: Option<(i64, Option<i64>)>,
+    /// set on the clips of a compound split into words, which aren't a recording of the term
+    #[serde(skip_serializing_if = "Option::is_none", skip_deserializing)]
+    pub compound: Option<CompoundPart>,
+}
+
+/// query parameters accepted by the lookup routes
+#[derive(Debug, Deserialize, Default)]
+pub struct IndexQuery {
+    pub term: Option<String>,
+    pub reading: Option<String>,
+    /// probe files that aren't in the metadata cache yet
+    #[serde(default)]
+    pub meta: bool,
+    /// drop clips longer than this (seconds)
+    pub max_duration: Option<f64>,
+    /// move clips no longer than this (seconds) to the front
+    pub prefer_under: Option<f64>,
+    pub sort_by: Option<MetaSort>,
+    /// serve loudness normalized audio at this target (LUFS)
+    pub normalize: Option<f64>,
+    /// serve audio with leading & trailing silence below this level (dB) removed
+    pub trim: Option<f64>,
+    /// serve audio slowed down / sped up by this factor, ex: 0.75
+    pub speed: Option<f64>,
+    /// add a clip of a split compound's words joined together (requires ffmpeg)
+    #[serde(default)]
+    pub concat: bool,
+    /// whether a latin `reading` is romaji (`ja`) or pinyin (`zh`), guessed when missing
+    pub lang: Option<Lang>,
+    /// the page of expressions a lookup without `term` returns, 1 based
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+    /// serve audio converted to this format. defaults to `ios` for iOS user agents
+    #[cfg(feature = "transcode")]
+    pub format: Option<crate::derived::AudioFormat>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    Ja,
+    Zh,
+}
+
+impl IndexQuery {
+    /// the reading to look up. romaji is looked up as hiragana (`hon` -> `ほん`) with `lang=ja`
+    /// or when the term has kana in it. toneless pinyin reads as romaji too (`ren`),
+    /// so anything else is looked up as sent
+    pub fn lookup_reading(&self) -> Option<String> {
+        let reading = self.reading.as_deref()?;
+        let japanese = match self.lang {
+            Some(lang) => lang == Lang::Ja,
+            None => self.term.as_deref().is_some_and(|t| t.chars().any(is_kana)),
+        };
+        let kana = japanese.then(|| romaji_to_hiragana(reading)).flatten();
+        Some(kana.unwrap_or_else(|| reading.to_string()))
+    }
+
+    pub fn paging(&self) -> Paging {
+        Paging {
+            page: self.page,
+            per_page: self.per_page,
+        }
+    }
+
+    /// the processing the generated audio urls ask the audio route for
+    pub fn derive_params(&self) -> DeriveParams {
+        DeriveParams {
+            normalize: self.normalize,
+            trim: self.trim,
+            speed: self.speed,
+            #[cfg(feature = "transcode")]
+            format: self.format,
+            // clip offsets come from the entry, not the request
+            ..Default::default()
+        }
+    }
+
+    /// whether the metadata of every result is needed to answer this query
+    pub fn needs_meta(&self) -> bool {
+        self.meta
+            || self.max_duration.is_some()
+            || self.prefer_under.is_some()
+            || self.sort_by.is_some()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetaSort {
+    /// shortest first
+    Duration,
+    /// loudest first
+    Loudness,
+    /// highest first
+    SampleRate,
 }
 
 impl AudioResult {
-    pub fn create_list(entries: &[DatabaseEntry]) -> Vec<AudioResult> {
+    pub fn create_list(entries: &[DatabaseEntry], derive: &DeriveParams) -> Vec<AudioResult> {
         let mut audio_sources_list: Vec<AudioResult> = Vec::with_capacity(entries.len());
         if !entries.is_empty() {
             let audio_files_res: Vec<AudioResult> = entries
                 .par_iter()
-                .filter_map(|e| e.to_audio_result().ok())
+                .filter_map(|e| e.to_audio_result(derive).ok())
                 .collect();
             audio_sources_list = audio_files_res;
         }
@@ -47,6 +163,114 @@ impl AudioResult {
             println!("    ▼ {}\n        {}", entry.name, entry.url);
         }
     }
+
+    /// applies the metadata filters & sorts of the query.
+    /// results with unknown metadata are kept and sorted last.
+    /// the parts of a split compound are left out, they stay complete and in `part` order.
+    pub fn apply_meta_query(list: &mut Vec<AudioResult>, query: &IndexQuery) {
+        let (mut words, parts): (Vec<_>, Vec<_>) = std::mem::take(list)
+            .into_iter()
+            .partition(|r| r.compound.is_none());
+        Self::apply_meta_query_to_words(&mut words, query);
+        words.extend(parts);
+        *list = words;
+    }
+
+    fn apply_meta_query_to_words(list: &mut Vec<AudioResult>, query: &IndexQuery) {
+        let duration = |r: &AudioResult| r.meta.as_ref().and_then(|m| m.duration_ms);
+        if let Some(max) = query.max_duration {
+            let max_ms = (max * 1000.0) as i64;
+            list.retain(|r| duration(r).is_none_or(|d| d <= max_ms));
+        }
+        // sorts are stable, so the source order from sort.txt survives within equal keys
+        if let Some(sort_by) = query.sort_by {
+            match sort_by {
+                MetaSort::Duration => list.sort_by_key(|r| duration(r).unwrap_or(i64::MAX)),
+                MetaSort::Loudness => list.sort_by(|a, b| {
+                    let l = |r: &AudioResult| {
+                        r.meta.as_ref().and_then(|m| m.loudness).unwrap_or(f64::MIN)
+                    };
+                    l(b).total_cmp(&l(a))
+                }),
+                MetaSort::SampleRate => list.sort_by_key(|r| {
+                    std::cmp::Reverse(r.meta.as_ref().and_then(|m| m.sample_rate).unwrap_or(0))
+                }),
+            }
+        }
+        if let Some(under) = query.prefer_under {
+            let under_ms = (under * 1000.0) as i64;
+            list.sort_by_key(|r| !duration(r).is_some_and(|d| d <= under_ms));
+        }
+    }
+}
+
+/// file name -> paths inside a packed source (blob database or archive),
+/// so entries resolve against the pack the same way `find_audio_file` resolves them on disk.
+#[derive(Debug, Default)]
+pub struct PackIndex {
+    files: HashMap<String, Vec<String>>,
+}
+
+impl PackIndex {
+    pub fn insert(&mut self, path: String) {
+        let name = path.rsplit('/').next().unwrap_or(&path).to_string();
+        self.files.entry(name).or_default().push(path);
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.values().map(Vec::len).sum()
+    }
+
+    /// the path of `file`, preferring the `media` folder, then the speaker's (`display`) folder.
+    pub fn find(&self, file: &str, display: &str) -> Option<&str> {
+        let paths = self.files.get(file)?;
+        let in_folder = |folder: &str| {
+            paths.iter().find(|p| {
+                let mut parts = p.rsplit('/');
+                parts.next();
+                parts.next() == Some(folder)
+            })
+        };
+        in_folder("media")
+            .or_else(|| in_folder(display))
+            .or_else(|| paths.first())
+            .map(String::as_str)
+    }
+}
+
+/// responds with an in-memory file (packed & archived sources), typed by its extension.
+/// supports single range requests like `NamedFile` does for files on disk.
+pub fn serve_bytes(req: &HttpRequest, path: &str, data: Vec<u8>) -> HttpResponse {
+    let ext = path.rsplit_once('.').map(|(_, e)| e).unwrap_or_default();
+    let mime = actix_files::file_extension_to_mime(ext);
+    let size = data.len() as u64;
+    let Some(range) = req.headers().get(header::RANGE) else {
+        return HttpResponse::Ok()
+            .content_type(mime)
+            .insert_header((header::ACCEPT_RANGES, "bytes"))
+            .body(data);
+    };
+    match range
+        .to_str()
+        .ok()
+        .and_then(|r| HttpRange::parse(r, size).ok())
+        .and_then(|ranges| ranges.into_iter().next())
+    {
+        Some(HttpRange { start, length }) => {
+            let (start, end) = (start as usize, (start + length) as usize);
+            HttpResponse::PartialContent()
+                .content_type(mime)
+                .insert_header((header::ACCEPT_RANGES, "bytes"))
+                .insert_header((
+                    header::CONTENT_RANGE,
+                    format!("bytes {start}-{}/{size}", end - 1),
+                ))
+                .body(data[start..end].to_vec())
+        }
+        None => HttpResponse::RangeNotSatisfiable()
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{size}")))
+            .finish(),
+    }
 }
 
 #[allow(dead_code)]
@@ -79,5 +303,262 @@ pub static KANA_MAP: LazyLock<BiHashMap<&'static str, &'static str>> = LazyLock:
         ("ギョ", "ぎょ"), ("ジャ", "じゃ"), ("ジュ", "じゅ"), ("ジョ", "じょ"),
         ("ビャ", "びゃ"), ("ビュ", "びゅ"), ("ビョ", "びょ"), ("ピャ", "ぴゃ"),
         ("ピュ", "ぴゅ"), ("ピョ", "ぴょ"),
+        ("ァ", "ぁ"), ("ィ", "ぃ"), ("ゥ", "ぅ"), ("ェ", "ぇ"), ("ォ", "ぉ"),
+        ("ャ", "ゃ"), ("ュ", "ゅ"), ("ョ", "ょ"), ("ッ", "っ"), ("ヮ", "ゎ"),
+        ("ヵ", "ゕ"), ("ヶ", "ゖ"), ("ヰ", "ゐ"), ("ヱ", "ゑ"), ("ヴ", "ゔ"),
     ])
 });
+
+/// hepburn & kunrei (nihon) romaji. `n` & double consonants are handled by `romaji_to_hiragana`
+#[rustfmt::skip]
+static ROMAJI_MAP: LazyLock<HashMap<&'static str, &'static str>> = LazyLock::new(|| {
+    HashMap::from_iter([
+        ("a", "あ"), ("i", "い"), ("u", "う"), ("e", "え"), ("o", "お"),
+        ("ka", "か"), ("ki", "き"), ("ku", "く"), ("ke", "け"), ("ko", "こ"),
+        ("sa", "さ"), ("shi", "し"), ("si", "し"), ("su", "す"), ("se", "せ"), ("so", "そ"),
+        ("ta", "た"), ("chi", "ち"), ("ti", "ち"), ("tsu", "つ"), ("tu", "つ"), ("te", "て"), ("to", "と"),
+        ("na", "な"), ("ni", "に"), ("nu", "ぬ"), ("ne", "ね"), ("no", "の"),
+        ("ha", "は"), ("hi", "ひ"), ("fu", "ふ"), ("hu", "ふ"), ("he", "へ"), ("ho", "ほ"),
+        ("ma", "ま"), ("mi", "み"), ("mu", "む"), ("me", "め"), ("mo", "も"),
+        ("ya", "や"), ("yu", "ゆ"), ("yo", "よ"),
+        ("ra", "ら"), ("ri", "り"), ("ru", "る"), ("re", "れ"), ("ro", "ろ"),
+        ("wa", "わ"), ("wo", "を"),
+        ("ga", "が"), ("gi", "ぎ"), ("gu", "ぐ"), ("ge", "げ"), ("go", "ご"),
+        ("za", "ざ"), ("ji", "じ"), ("zi", "じ"), ("zu", "ず"), ("ze", "ぜ"), ("zo", "ぞ"),
+        ("da", "だ"), ("di", "ぢ"), ("du", "づ"), ("de", "で"), ("do", "ど"),
+        ("ba", "ば"), ("bi", "び"), ("bu", "ぶ"), ("be", "べ"), ("bo", "ぼ"),
+        ("pa", "ぱ"), ("pi", "ぴ"), ("pu", "ぷ"), ("pe", "ぺ"), ("po", "ぽ"),
+        ("kya", "きゃ"), ("kyu", "きゅ"), ("kyo", "きょ"),
+        ("sha", "しゃ"), ("shu", "しゅ"), ("she", "しぇ"), ("sho", "しょ"),
+        ("sya", "しゃ"), ("syu", "しゅ"), ("syo", "しょ"),
+        ("cha", "ちゃ"), ("chu", "ちゅ"), ("che", "ちぇ"), ("cho", "ちょ"),
+        ("tya", "ちゃ"), ("tyu", "ちゅ"), ("tyo", "ちょ"),
+        ("nya", "にゃ"), ("nyu", "にゅ"), ("nyo", "にょ"),
+        ("hya", "ひゃ"), ("hyu", "ひゅ"), ("hyo", "ひょ"),
+        ("mya", "みゃ"), ("myu", "みゅ"), ("myo", "みょ"),
+        ("rya", "りゃ"), ("ryu", "りゅ"), ("ryo", "りょ"),
+        ("gya", "ぎゃ"), ("gyu", "ぎゅ"), ("gyo", "ぎょ"),
+        ("ja", "じゃ"), ("ju", "じゅ"), ("je", "じぇ"), ("jo", "じょ"),
+        ("zya", "じゃ"), ("zyu", "じゅ"), ("zyo", "じょ"),
+        ("jya", "じゃ"), ("jyu", "じゅ"), ("jyo", "じょ"),
+        ("dya", "ぢゃ"), ("dyu", "ぢゅ"), ("dyo", "ぢょ"),
+        ("bya", "びゃ"), ("byu", "びゅ"), ("byo", "びょ"),
+        ("pya", "ぴゃ"), ("pyu", "ぴゅ"), ("pyo", "ぴょ"),
+        ("fa", "ふぁ"), ("fi", "ふぃ"), ("fe", "ふぇ"), ("fo", "ふぉ"), ("vu", "ゔ"),
+        ("-", "ー"),
+    ])
+});
+
+/// romaji -> hiragana, `None` unless all of it is romaji.
+/// long vowels can be written with a macron or circumflex (tōkyō, tôkyô)
+pub fn romaji_to_hiragana(romaji: &str) -> Option<String> {
+    let mut chars = Vec::new();
+    for c in romaji.to_lowercase().chars() {
+        match c {
+            'ā' | 'â' => chars.extend(['a', 'a']),
+            'ī' | 'î' => chars.extend(['i', 'i']),
+            'ū' | 'û' => chars.extend(['u', 'u']),
+            'ē' | 'ê' => chars.extend(['e', 'e']),
+            'ō' | 'ô' => chars.extend(['o', 'u']),
+            ' ' => {}
+            _ => chars.push(c),
+        }
+    }
+    let starts_syllable = |c: Option<&char>| c.is_some_and(|c| "aiueoy".contains(*c));
+
+    let mut kana = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (c, next) = (chars[i], chars.get(i + 1));
+        // ん: an n that doesn't start a syllable (hon, hon'ya, honn), or m before b/m/p (shimbun)
+        if (c == 'n' && !starts_syllable(next))
+            || (c == 'm' && next.is_some_and(|n| "bmp".contains(*n)))
+        {
+            kana.push('ん');
+            i += match next {
+                Some('\'') => 2,
+                Some('n') if !starts_syllable(chars.get(i + 2)) => 2,
+                _ => 1,
+            };
+            continue;
+        }
+        // っ: a doubled consonant (kitte), or the t of tch (matcha)
+        if c.is_ascii_lowercase()
+            && !"aiueon".contains(c)
+            && (next == Some(&c) || (c == 't' && next == Some(&'c')))
+        {
+            kana.push('っ');
+            i += 1;
+            continue;
+        }
+        let (len, hiragana) = (1..=3).rev().find_map(|len| {
+            let syllable: String = chars.get(i..i + len)?.iter().collect();
+            Some((len, *ROMAJI_MAP.get(syllable.as_str())?))
+        })?;
+        kana.push_str(hiragana);
+        i += len;
+    }
+    (!kana.is_empty()).then_some(kana)
+}
+
+/// whether `c` is hiragana or katakana (ー included)
+pub fn is_kana(c: char) -> bool {
+    let mut tmp = [0u8; 4];
+    let c_str = &*c.encode_utf8(&mut tmp);
+    c == 'ー' || KANA_MAP.contains_left(c_str) || KANA_MAP.contains_right(c_str)
+}
+
+/// hiragana -> katakana
+pub fn to_katakana(kana: &str) -> String {
+    let mut tmp = [0u8; 4];
+    kana.chars()
+        .map(|c| {
+            let c = &*c.encode_utf8(&mut tmp);
+            KANA_MAP.get_by_right(c).copied().unwrap_or(c).to_string()
+        })
+        .collect()
+}
+
+/// katakana -> hiragana, so both spellings of a reading compare equal
+pub fn to_hiragana(kana: &str) -> String {
+    let mut tmp = [0u8; 4];
+    kana.chars()
+        .map(|c| {
+            let c = &*c.encode_utf8(&mut tmp);
+            KANA_MAP.get_by_left(c).copied().unwrap_or(c).to_string()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_kana, romaji_to_hiragana, to_hiragana, to_katakana, AudioResult, IndexQuery, Lang,
+        MetaSort, PackIndex,
+    };
+    use crate::database::CompoundPart;
+    use crate::metadata::AudioMeta;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn kana_normalization() {
+        assert_eq!(to_hiragana("ヴァイオリン"), "ゔぁいおりん");
+        assert_eq!(to_hiragana("キッテ"), "きって");
+        assert_eq!(to_katakana("ちょっと"), "チョット");
+        // not kana, left alone
+        assert_eq!(to_hiragana("本ホン"), "本ほん");
+        assert!(is_kana('ー') && is_kana('ッ') && is_kana('ゔ'));
+        assert!(!is_kana('本') && !is_kana('a'));
+    }
+
+    #[test]
+    fn romaji() {
+        for (romaji, kana) in [
+            ("hon", "ほん"),
+            ("Nihon", "にほん"),
+            ("tōkyō", "とうきょう"),
+            ("toukyou", "とうきょう"),
+            ("shimbun", "しんぶん"),
+            ("sinbun", "しんぶん"),
+            ("kitte", "きって"),
+            ("matcha", "まっちゃ"),
+            ("tyotto", "ちょっと"),
+            ("hon'ya", "ほんや"),
+            ("honn", "ほん"),
+            ("konnichiwa", "こんにちわ"),
+            ("ra-men", "らーめん"),
+        ] {
+            assert_eq!(
+                romaji_to_hiragana(romaji).as_deref(),
+                Some(kana),
+                "{romaji}"
+            );
+        }
+        // pinyin & anything else that isn't all romaji
+        assert_eq!(romaji_to_hiragana("xing"), None);
+        assert_eq!(romaji_to_hiragana("hang"), None);
+        assert_eq!(romaji_to_hiragana("ほん"), None);
+    }
+
+    #[test]
+    fn lookup_reading() {
+        let query = |term: &str, reading: &str, lang| IndexQuery {
+            term: Some(term.to_string()),
+            reading: Some(reading.to_string()),
+            lang,
+            ..Default::default()
+        };
+        // toneless pinyin is looked up as sent
+        assert_eq!(query("人", "ren", None).lookup_reading().unwrap(), "ren");
+        assert_eq!(query("好", "hao", None).lookup_reading().unwrap(), "hao");
+        assert_eq!(
+            query("好", "hao", Some(Lang::Zh)).lookup_reading().unwrap(),
+            "hao"
+        );
+        assert_eq!(
+            query("本", "hon", Some(Lang::Ja)).lookup_reading().unwrap(),
+            "ほん"
+        );
+        assert_eq!(
+            query("読む", "yomu", None).lookup_reading().unwrap(),
+            "よむ"
+        );
+        assert_eq!(
+            query("読む", "yomu", Some(Lang::Zh))
+                .lookup_reading()
+                .unwrap(),
+            "yomu"
+        );
+        assert_eq!(query("行", "xíng", None).lookup_reading().unwrap(), "xíng");
+        assert_eq!(
+            query("行", "xing2", None).lookup_reading().unwrap(),
+            "xing2"
+        );
+    }
+
+    #[test]
+    fn meta_query_keeps_compound_parts() {
+        let result = |name: &str, duration_ms, part: Option<usize>| AudioResult {
+            name: name.to_string(),
+            url: String::new(),
+            meta: Some(AudioMeta {
+                duration_ms: Some(duration_ms),
+                ..Default::default()
+            }),
+            path: None,
+            clip: None,
+            compound: part.map(|part| CompoundPart {
+                part: Some(part),
+                parts: 2,
+                expression: name.to_string(),
+            }),
+        };
+        let mut list = vec![
+            result("long", 900, None),
+            result("short", 300, None),
+            result("日本語", 2000, Some(1)),
+            result("学校", 500, Some(2)),
+        ];
+        let query = IndexQuery {
+            sort_by: Some(MetaSort::Duration),
+            max_duration: Some(1.0),
+            ..Default::default()
+        };
+        AudioResult::apply_meta_query(&mut list, &query);
+        let names: Vec<_> = list.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["short", "long", "日本語", "学校"]);
+    }
+
+    #[test]
+    fn pack_index_find() {
+        let mut index = PackIndex::default();
+        index.insert("spk1/本.mp3".to_string());
+        index.insert("spk2/本.mp3".to_string());
+        index.insert("media/日本.mp3".to_string());
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.find("本.mp3", "spk2"), Some("spk2/本.mp3"));
+        assert_eq!(index.find("本.mp3", "spk3"), Some("spk1/本.mp3"));
+        assert_eq!(index.find("日本.mp3", "spk1"), Some("media/日本.mp3"));
+        assert_eq!(index.find("二本.mp3", ""), None);
+    }
+}
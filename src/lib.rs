@@ -165,7 +165,9 @@ async fn create_test_table(pool: &sqlx::SqlitePool) {
         source TEXT NOT NULL,
         speaker TEXT,
         display TEXT,
-        file TEXT NOT NULL
+        file TEXT NOT NULL,
+        start_ms INTEGER,
+        end_ms INTEGER
     )",
     )
     .execute(pool)
@@ -1,10 +1,25 @@
 #![allow(unused_imports, clippy::result_large_err)]
+#![cfg_attr(test, feature(test))]
+mod archive;
+mod blob;
+mod chinese;
 mod cli;
 mod config;
 mod database;
+mod deinflect;
+mod derived;
 mod helper;
-
-use crate::helper::AudioResult;
+mod memory;
+mod metadata;
+mod migrate;
+mod search;
+mod speakers;
+mod user;
+mod variants;
+
+use crate::archive::ZipPack;
+use crate::blob::BlobPack;
+use crate::helper::{AudioResult, IndexQuery};
 
 use actix_web::{
     http::header::ContentType, middleware, web, App, HttpRequest, HttpResponse, HttpServer,
@@ -12,12 +27,15 @@ use actix_web::{
 };
 
 use clap::Parser;
-use cli::{Cli, CliLog};
+use cli::{BlockCommand, Cli, CliCommand, CliLog, PinCommand};
 use color_eyre::eyre::eyre;
 use color_print::{ceprintln, cprintln};
-use config::spawn_headless;
-use database::{AudioSource, DatabaseEntry};
+use config::{spawn_headless, AudioRoots, Config};
+use database::{AudioSource, CompoundPart, DatabaseEntry, SortOrder};
+use derived::DeriveParams;
 use json::eprint_pretty;
+use memory::MemoryIndex;
+use metadata::MetaCache;
 use sqlx::SqlitePool;
 use std::ffi::OsString;
 use std::fmt::Debug;
@@ -32,6 +50,7 @@ use tracing::debug;
 use tracing_subscriber::EnvFilter;
 #[cfg(target_os = "windows")]
 use tray_item::{IconSource, TrayItem};
+use user::UserDb;
 
 pub(crate) struct ProgramInfo {
     pub pkg_name: String,
@@ -39,16 +58,18 @@ pub(crate) struct ProgramInfo {
     pub current_exe: PathBuf,
     pub cli: Cli,
     pub db: SqlitePool,
-    pub sort: Vec<AudioSource>,
+    pub user_db: UserDb,
+    pub meta: MetaCache,
+    pub memory: Option<MemoryIndex>,
+    pub blobs: HashMap<AudioSource, BlobPack>,
+    pub zips: HashMap<AudioSource, ZipPack>,
+    pub sort: SortOrder,
+    pub config: Config,
+    pub roots: AudioRoots,
 }
 
 pub(crate) static PROGRAM_INFO: OnceCell<ProgramInfo> = OnceCell::const_new();
 async fn init_program() -> ProgramInfo {
-    let dbpath = Path::new("./entries.db");
-    if !dbpath.exists() {
-        println!("you are missing an entries.db file in the main directory.\ndownload the latest entries.db:\nhttps://github.com/aramrw/yomichan_audio_server/releases/download/v0.0.1/entries.db");
-    }
-
     fn print_arg(arg: &str, x: impl Debug) {
         cprintln!("<b>--{arg}</>: {x:?}");
     }
@@ -61,26 +82,107 @@ async fn init_program() -> ProgramInfo {
     let pkg_name = env!("CARGO_PKG_NAME").to_string();
     print_arg("port", &cli.port.inner);
     print_arg("log", cli.log);
+    print_arg("audio", &cli.audio);
+    print_arg("db", &cli.db);
 
     // init database
-    let buf = include_bytes!("../entries.db");
-    let mut db_file = std::fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open("entries.db")
-        .unwrap();
-    db_file.write_all(buf).unwrap();
-    let db = SqlitePool::connect("entries.db").await.unwrap();
-
-    let sort = AudioSource::read_sort_file();
+    #[cfg(feature = "embedded-db")]
+    if let Err(e) = database::extract_embedded_db(&cli.db) {
+        ceprintln!("<r>[error]</> failed to extract the embedded entries.db: {e}");
+    }
+    if !cli.db.exists() {
+        ceprintln!(
+            "\n<r>[error]</> you are missing an entries.db file at: {}",
+            cli.db.display()
+        );
+        ceprintln!("<cyan>[help]</> download the latest entries.db:\nhttps://github.com/aramrw/yomichan_audio_server/releases/download/v0.0.1/entries.db");
+        ceprintln!("<cyan>[help]</> or run with: <b>--db PATH</>");
+        process::exit(1);
+    }
+    // the headless parent only spawns the background server and exits,
+    // so the upgrade and the index are left to the child that actually serves
+    let serves = cli.command.is_none() && !cli.sources && cli.log != CliLog::Headless;
+    // `migrate` reports (and fails on) what it applies itself. the server still works
+    // with an older database, so a read-only or shipped one only gets a warning
+    if serves {
+        if let Err(e) = migrate::upgrade(&cli.db).await {
+            ceprintln!(
+                "<y>[warning]</> failed to migrate {}: {e}",
+                cli.db.display()
+            );
+            ceprintln!(
+                "<cyan>[help]</> make sure the file is writable, or run the <b>migrate</> command"
+            );
+        }
+    }
+    let writable = cli.command.as_ref().is_some_and(CliCommand::writes_db);
+    let db = match database::connect(&cli.db, writable).await {
+        Ok(db) => db,
+        Err(e) => {
+            ceprintln!("<r>[error]</> failed to open {}: {e}", cli.db.display());
+            process::exit(1);
+        }
+    };
+    let user_db = match UserDb::open(&cli.user_db).await {
+        Ok(db) => db,
+        Err(e) => {
+            ceprintln!(
+                "<r>[error]</> failed to open {}: {e}",
+                cli.user_db.display()
+            );
+            process::exit(1);
+        }
+    };
+    let meta = match MetaCache::open(&cli.cache).await {
+        Ok(meta) => meta,
+        Err(e) => {
+            ceprintln!(
+                "<r>[error]</> failed to open the cache at {:?}: {e}",
+                cli.cache
+            );
+            process::exit(1);
+        }
+    };
+
+    let config = Config::read_config_file();
+    let roots = AudioRoots::new(&cli.audio, &config);
+    let blobs = blob::open_packs(&roots).await;
+    let zips = archive::open_zips(&roots);
+
+    let sort = SortOrder::read_sort_file();
+    let memory = if cli.memory && serves {
+        let start = std::time::Instant::now();
+        match MemoryIndex::load(&db, &sort).await {
+            Ok(memory) => {
+                cprintln!(
+                    "<i><g>+</> loaded {} entries into memory in {:.1?}</>",
+                    memory.len(),
+                    start.elapsed()
+                );
+                Some(memory)
+            }
+            Err(e) => {
+                ceprintln!("<r>[error]</> failed to load entries into memory: {e}");
+                None
+            }
+        }
+    } else {
+        None
+    };
     ProgramInfo {
         pkg_name,
         version,
         current_exe,
         cli,
         db,
+        user_db,
+        meta,
+        memory,
+        blobs,
+        zips,
         sort,
+        config,
+        roots,
     }
 }
 
@@ -94,6 +196,38 @@ async fn main() -> io::Result<()> {
         process::exit(0);
     }
 
+    if let Some(cmd) = &pi.cli.command {
+        let res: color_eyre::Result<()> = match cmd {
+            CliCommand::Scan { force } => {
+                let mut res = Ok(());
+                for root in pi.roots.all() {
+                    res = pi.meta.scan(root, *force).await.map_err(Into::into);
+                    if res.is_err() {
+                        break;
+                    }
+                }
+                res
+            }
+            CliCommand::Import { source, dir } => match AudioSource::from_str(source) {
+                Ok(kind) if kind != AudioSource::Other => {
+                    let root = pi.roots.get(&kind);
+                    let dir = dir.clone().unwrap_or_else(|| root.join(source));
+                    let out = root.join(format!("{source}.db"));
+                    blob::import(&dir, &out).await.map_err(Into::into)
+                }
+                _ => Err(eyre!("unknown source {source}, see --sources")),
+            },
+            CliCommand::Migrate => run_migrate_command().await,
+            CliCommand::Pin { command } => run_pin_command(command).await,
+            CliCommand::Block { command } => run_block_command(command).await,
+        };
+        if let Err(e) = res {
+            eprint_pretty!(e);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
     let pkg_name = &pi.pkg_name;
 
     let init_fulltrace_subscriber = || {
@@ -102,25 +236,26 @@ async fn main() -> io::Result<()> {
             .init();
     };
 
-    let audio_path = &pi.cli.audio;
-    if !audio_path.exists() {
-        let default = OsString::from("yas*");
-        let current_exe = pi.current_exe.file_name().unwrap_or(&default).display();
-        ceprintln!(
-            "\n<r>[error]</> The 'audio' folder was not found at: {}",
-            audio_path.display()
-        );
-        ceprintln!("<cyan>[help]</> create one in the same folder as the exe",);
-        ceprintln!("<cyan>[help]</> or run with: {current_exe} <b>--audio PATH</>");
+    for audio_path in pi.roots.all() {
+        if !audio_path.exists() {
+            let default = OsString::from("yas*");
+            let current_exe = pi.current_exe.file_name().unwrap_or(&default).display();
+            ceprintln!(
+                "\n<r>[error]</> The 'audio' folder was not found at: {}",
+                audio_path.display()
+            );
+            ceprintln!("<cyan>[help]</> create one in the same folder as the exe",);
+            ceprintln!("<cyan>[help]</> or run with: {current_exe} <b>--audio PATH</>");
 
-        process::exit(1);
-    }
-    let paths: Vec<PathBuf> = read_dir(audio_path)?.flatten().map(|f| f.path()).collect();
-    if paths.is_empty() {
-        ceprintln!(
-            "\n<r>[error]</> {audio_path:?} folder contains no files to serve <r>[EXITCODE 1]</r>"
-        );
-        process::exit(1);
+            process::exit(1);
+        }
+        let paths: Vec<PathBuf> = read_dir(audio_path)?.flatten().map(|f| f.path()).collect();
+        if paths.is_empty() {
+            ceprintln!(
+                "\n<r>[error]</> {audio_path:?} folder contains no files to serve <r>[EXITCODE 1]</r>"
+            );
+            process::exit(1);
+        }
     }
 
     match pi.cli.log {
@@ -141,8 +276,18 @@ async fn main() -> io::Result<()> {
     let server = HttpServer::new(|| {
         App::new()
             .wrap(middleware::Logger::default())
-            .service(actix_files::Files::new("/audio", &pi.cli.audio))
+            .route("/audio/{path:.*}", web::get().to(serve_audio))
+            .route("/blob/{source}/{path:.*}", web::get().to(serve_blob))
             .route("/", web::get().to(index))
+            .route("/meta", web::get().to(meta))
+            .route("/search", web::get().to(search))
+            .route("/concat", web::get().to(serve_concat))
+            .route("/pins", web::get().to(list_pins))
+            .route("/pins", web::post().to(add_pin))
+            .route("/pins", web::delete().to(remove_pin))
+            .route("/blocklist", web::get().to(list_blocks))
+            .route("/blocklist", web::post().to(add_block))
+            .route("/blocklist", web::delete().to(remove_block))
     })
     .bind(&pi.cli.port.inner)?
     .run();
@@ -156,17 +301,356 @@ async fn main() -> io::Result<()> {
 }
 
 async fn index(req: HttpRequest) -> impl Responder {
+    lookup(req, false).await
+}
+
+/// same as `index`, but every result carries its duration, format & loudness.
+async fn meta(req: HttpRequest) -> impl Responder {
+    lookup(req, true).await
+}
+
+/// a clip of the first file of every part of a split compound joined together, served by `concat`.
+/// only files on disk can be joined, and not ones cut out of a longer recording,
+/// so the first file of a part that is both is used.
+fn compound_clip(list: &[AudioResult], derive: &DeriveParams) -> Option<AudioResult> {
+    let pi = PROGRAM_INFO.get().unwrap();
+    let audio_url = format!("http://{}/audio/", pi.cli.port.inner);
+    let first = list.iter().find_map(|r| r.compound.as_ref())?;
+    let mut parts = Vec::with_capacity(first.parts);
+    let mut words = Vec::with_capacity(first.parts);
+    for part in 1..=first.parts {
+        // packed sources are served under `/audio/` too, but `concat` only reads the disk
+        let result = list.iter().find(|r| {
+            r.path.is_some()
+                && r.clip.is_none()
+                && r.compound.as_ref().is_some_and(|c| c.part == Some(part))
+        })?;
+        words.push(result.compound.as_ref()?.expression.as_str());
+        let (path, _) = result.url.split_once('?').unwrap_or((&result.url, ""));
+        let rel = path.strip_prefix(&audio_url)?;
+        parts.push(
+            rel.replace('%', "%25")
+                .replace('&', "%26")
+                .replace('+', "%2B")
+                .replace('#', "%23"),
+        );
+    }
+
+    let mut url = format!(
+        "http://{}/concat?parts={}",
+        pi.cli.port.inner,
+        parts.join("|")
+    );
+    if !derive.is_empty() {
+        url = format!("{url}&{}", derive.to_query());
+    }
+    let expression = words.concat();
+    Some(AudioResult {
+        name: format!("compound {expression}"),
+        url,
+        meta: None,
+        path: None,
+        clip: None,
+        compound: Some(CompoundPart {
+            part: None,
+            parts: first.parts,
+            expression,
+        }),
+    })
+}
+
+/// `/?reading=ほん` without a term: every expression read that way, each with its audio
+async fn reverse_lookup(query: &IndexQuery, reading: &str) -> HttpResponse {
     let pi = &PROGRAM_INFO.get().unwrap();
-    // access query parameters
-    let query =
-        match actix_web::web::Query::<HashMap<String, String>>::from_query(req.query_string()) {
-            Ok(q) => q,
-            Err(e) => return HttpResponse::from_error(e),
+    let derive = query.derive_params();
+    if let Err(e) = derive.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    let paging = query.paging();
+    let (total, groups) = match database::query_by_reading(reading, paging).await {
+        Ok(res) => res,
+        Err(e) => {
+            eprint_pretty!(e);
+            return HttpResponse::from_error(Error::other(e));
+        }
+    };
+
+    let mut expressions = Vec::new();
+    for (expression, reading, entries) in groups {
+        let mut audio_source_list = AudioResult::create_list(&entries, &derive);
+        pi.meta
+            .attach(&mut audio_source_list, query.needs_meta())
+            .await;
+        AudioResult::apply_meta_query(&mut audio_source_list, query);
+        if audio_source_list.is_empty() {
+            continue;
+        }
+        expressions.push(serde_json::json!({
+            "expression": expression,
+            "reading": reading,
+            "audioSources": audio_source_list,
+        }));
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "type": "readingLookup",
+        "reading": reading,
+        "page": paging.page(),
+        "perPage": paging.per_page(),
+        "total": total,
+        "expressions": expressions,
+    }))
+}
+
+async fn run_migrate_command() -> color_eyre::Result<()> {
+    let pi = PROGRAM_INFO.get().unwrap();
+    let applied = migrate::run(&pi.db).await?;
+    if applied.is_empty() {
+        cprintln!(
+            "{} is up to date (v{})",
+            pi.cli.db.display(),
+            migrate::latest_version()
+        );
+    }
+    for migration in applied {
+        cprintln!(
+            "<g>+</> migrated to v{} ({})",
+            migration.version,
+            migration.name
+        );
+    }
+    let start = std::time::Instant::now();
+    if migrate::build_search_index(&pi.db).await? {
+        cprintln!(
+            "<g>+</> built the substring search index in {:.1?}",
+            start.elapsed()
+        );
+    }
+    Ok(())
+}
+
+async fn run_pin_command(cmd: &PinCommand) -> color_eyre::Result<()> {
+    let user_db = &PROGRAM_INFO.get().unwrap().user_db;
+    match cmd {
+        PinCommand::Add {
+            expression,
+            reading,
+            source,
+            file,
+            speaker,
+        } => {
+            let pin = user::Pin {
+                expression: expression.clone(),
+                reading: reading.clone(),
+                source: source.clone(),
+                speaker: speaker.clone(),
+                file: file.clone(),
+            };
+            user_db.pin(&pin).await?;
+            cprintln!("<g>+</> pinned {source} {file} for {expression} {reading}");
+        }
+        PinCommand::Remove {
+            expression,
+            reading,
+        } => match user_db.unpin(expression, reading).await? {
+            true => cprintln!("<r>-</> unpinned {expression} {reading}"),
+            false => cprintln!("<y>{expression} {reading} isn't pinned</>"),
+        },
+        PinCommand::List => {
+            for pin in user_db.pins().await? {
+                let speaker = pin.speaker.unwrap_or_default();
+                cprintln!(
+                    "<b>{}</> {}: {} {} {speaker}",
+                    pin.expression,
+                    pin.reading,
+                    pin.source,
+                    pin.file
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_block_command(cmd: &BlockCommand) -> color_eyre::Result<()> {
+    let user_db = &PROGRAM_INFO.get().unwrap().user_db;
+    let to_block = |args: &cli::BlockArgs| {
+        let block = user::Block {
+            expression: args.expression.clone(),
+            speaker: args.speaker.clone(),
+            file: args.file.clone(),
         };
+        match block.is_valid() {
+            true => Ok(block),
+            false => Err(eyre!("a block needs a --speaker or a --file")),
+        }
+    };
+    match cmd {
+        BlockCommand::Add(args) => {
+            let block = to_block(args)?;
+            match user_db.block(&block).await? {
+                true => cprintln!("<g>+</> blocked {block}"),
+                false => cprintln!("<y>{block} is already blocked</>"),
+            }
+        }
+        BlockCommand::Remove(args) => {
+            let block = to_block(args)?;
+            match user_db.unblock(&block).await? {
+                true => cprintln!("<r>-</> unblocked {block}"),
+                false => cprintln!("<y>{block} isn't blocked</>"),
+            }
+        }
+        BlockCommand::List => {
+            for block in user_db.blocklist().await? {
+                println!("{block}");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn list_blocks() -> HttpResponse {
+    match PROGRAM_INFO.get().unwrap().user_db.blocklist().await {
+        Ok(blocks) => HttpResponse::Ok().json(serde_json::json!({
+            "type": "blocklist",
+            "blocklist": blocks,
+        })),
+        Err(e) => {
+            eprint_pretty!(e);
+            HttpResponse::from_error(Error::other(e))
+        }
+    }
+}
+
+/// blocks the entries matching the json body (see `user::Block`)
+async fn add_block(block: web::Json<user::Block>) -> HttpResponse {
+    if !block.is_valid() {
+        return HttpResponse::BadRequest().body("a block needs a speaker or a file");
+    }
+    match PROGRAM_INFO.get().unwrap().user_db.block(&block).await {
+        Ok(_) => HttpResponse::Ok().json(block.into_inner()),
+        Err(e) => {
+            eprint_pretty!(e);
+            HttpResponse::from_error(Error::other(e))
+        }
+    }
+}
+
+/// `DELETE /blocklist?speaker=..&file=..&expression=..`, the fields it was added with
+async fn remove_block(block: web::Query<user::Block>) -> HttpResponse {
+    match PROGRAM_INFO.get().unwrap().user_db.unblock(&block).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::NotFound().body("no such block"),
+        Err(e) => {
+            eprint_pretty!(e);
+            HttpResponse::from_error(Error::other(e))
+        }
+    }
+}
+
+/// `DELETE /pins?term=本&reading=ほん`
+#[derive(serde::Deserialize)]
+struct PinQuery {
+    term: String,
+    #[serde(default)]
+    reading: String,
+}
+
+async fn list_pins() -> HttpResponse {
+    match PROGRAM_INFO.get().unwrap().user_db.pins().await {
+        Ok(pins) => HttpResponse::Ok().json(serde_json::json!({
+            "type": "pins",
+            "pins": pins,
+        })),
+        Err(e) => {
+            eprint_pretty!(e);
+            HttpResponse::from_error(Error::other(e))
+        }
+    }
+}
+
+/// pins the entry in the json body (see `user::Pin`), replacing the previous pin of its word
+async fn add_pin(pin: web::Json<user::Pin>) -> HttpResponse {
+    let mut pin = pin.into_inner();
+    pin.source = match cli::parse_source(&pin.source) {
+        Ok(source) => source,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match PROGRAM_INFO.get().unwrap().user_db.pin(&pin).await {
+        Ok(()) => HttpResponse::Ok().json(pin),
+        Err(e) => {
+            eprint_pretty!(e);
+            HttpResponse::from_error(Error::other(e))
+        }
+    }
+}
+
+async fn remove_pin(query: web::Query<PinQuery>) -> HttpResponse {
+    let user_db = &PROGRAM_INFO.get().unwrap().user_db;
+    match user_db.unpin(&query.term, &query.reading).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::NotFound().body("no pin for this term & reading"),
+        Err(e) => {
+            eprint_pretty!(e);
+            HttpResponse::from_error(Error::other(e))
+        }
+    }
+}
+
+/// which words have audio at all, paginated. see `search::SearchQuery`
+async fn search(req: HttpRequest) -> HttpResponse {
+    let pi = &PROGRAM_INFO.get().unwrap();
+    let query = match web::Query::<search::SearchQuery>::from_query(req.query_string()) {
+        Ok(q) => q.into_inner(),
+        Err(e) => return HttpResponse::from_error(e),
+    };
+    if query.q.trim().is_empty() {
+        return HttpResponse::BadRequest().body("Missing query parameter: 'q'.");
+    }
+    let page = match search::search(
+        &pi.db,
+        &pi.user_db,
+        pi.memory.as_ref(),
+        &query,
+        pi.config.overlay,
+    )
+    .await
+    {
+        Ok(page) => page,
+        Err(e) => {
+            eprint_pretty!(e);
+            return HttpResponse::from_error(Error::other(e));
+        }
+    };
+    HttpResponse::Ok().json(serde_json::json!({
+        "type": "searchResults",
+        "query": query.q,
+        "mode": query.mode,
+        "page": query.paging().page(),
+        "perPage": query.paging().per_page(),
+        "total": page.total,
+        "hits": page.hits,
+    }))
+}
+
+async fn lookup(req: HttpRequest, with_meta: bool) -> HttpResponse {
+    let pi = &PROGRAM_INFO.get().unwrap();
+    // access query parameters
+    let mut query = match actix_web::web::Query::<IndexQuery>::from_query(req.query_string()) {
+        Ok(q) => q.into_inner(),
+        Err(e) => return HttpResponse::from_error(e),
+    };
+    query.meta |= with_meta;
+    #[cfg(feature = "transcode")]
+    if query.format.is_none() && is_ios(&req) {
+        query.format = Some(derived::AudioFormat::Ios);
+    }
     let start = std::time::Instant::now();
-    let (Some(term), Some(reading)) = (query.get("term"), query.get("reading")) else {
+    let Some(reading) = query.lookup_reading() else {
         return HttpResponse::BadRequest().body("Missing query parameters: 'term' and 'reading'.");
     };
+    let Some(term) = &query.term else {
+        return reverse_lookup(&query, &reading).await;
+    };
 
     // if !program.exists() {
     //     let e = DbError::MissingAudioFolder(pi.current_exe.clone());
@@ -175,7 +659,7 @@ async fn index(req: HttpRequest) -> impl Responder {
     //     std::process::exit(1);
     // }
 
-    let entries: Vec<DatabaseEntry> = match database::query_database(term, reading).await {
+    let entries: Vec<DatabaseEntry> = match database::query_database(term, &reading).await {
         Ok(res) => res,
         Err(e) => {
             eprint_pretty!(e);
@@ -183,7 +667,20 @@ async fn index(req: HttpRequest) -> impl Responder {
         }
     };
 
-    let audio_source_list = AudioResult::create_list(&entries);
+    let derive = query.derive_params();
+    if let Err(e) = derive.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    let mut audio_source_list = AudioResult::create_list(&entries, &derive);
+    pi.meta
+        .attach(&mut audio_source_list, query.needs_meta())
+        .await;
+    AudioResult::apply_meta_query(&mut audio_source_list, &query);
+    if query.concat {
+        if let Some(clip) = compound_clip(&audio_source_list, &derive) {
+            audio_source_list.insert(0, clip);
+        }
+    }
 
     match pi.cli.log {
         CliLog::Dev | CliLog::Full => {
@@ -215,6 +712,130 @@ async fn index(req: HttpRequest) -> impl Responder {
         .json(resp)
 }
 
+#[cfg(feature = "transcode")]
+fn is_ios(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(actix_web::http::header::USER_AGENT)
+        .and_then(|ua| ua.to_str().ok())
+        .is_some_and(|ua| ["iPhone", "iPad", "iPod"].iter().any(|d| ua.contains(d)))
+}
+
+/// serves a file from the audio folder, processed first if the query asks for it
+/// (ex: `?normalize=-16&trim=-50&speed=0.75`). processed files are cached, untouched files are served as is.
+async fn serve_audio(req: HttpRequest, path: web::Path<String>) -> actix_web::Result<HttpResponse> {
+    let pi = PROGRAM_INFO.get().unwrap();
+    let params = web::Query::<DeriveParams>::from_query(req.query_string())?.into_inner();
+    if let Some((source, rel)) = path.split_once('/') {
+        if let Some(zip) = AudioSource::from_str(source)
+            .ok()
+            .and_then(|s| pi.zips.get(&s))
+        {
+            let entry = rel.to_string();
+            let Some(data) = web::block(move || zip.read(&entry)).await?? else {
+                return Ok(HttpResponse::NotFound().finish());
+            };
+            if params.for_file(Path::new(rel)).is_empty() {
+                return Ok(helper::serve_bytes(&req, rel, data));
+            }
+            let (cache, rel) = (pi.cli.cache.clone(), rel.to_string());
+            let derived =
+                web::block(move || derived::derive_bytes(&rel, &data, &params, &cache)).await??;
+            return Ok(actix_files::NamedFile::open_async(derived)
+                .await?
+                .into_response(&req));
+        }
+    }
+    // urls start with the source, which decides the audio folder the file is in
+    let source = path.split('/').next().unwrap_or_default();
+    let root = pi
+        .roots
+        .get(&AudioSource::from_str(source).unwrap_or_default());
+    let Some(file) = derived::safe_join(root, &path) else {
+        return Ok(HttpResponse::BadRequest().body("invalid audio path"));
+    };
+    if params.is_empty() {
+        return Ok(actix_files::NamedFile::open_async(file)
+            .await?
+            .into_response(&req));
+    }
+
+    let hash = pi.meta.file_hash(&file).await.map_err(Error::other)?;
+    let cache = pi.cli.cache.clone();
+    let derived = web::block(move || derived::derive(&file, &hash, &params, &cache)).await??;
+    Ok(actix_files::NamedFile::open_async(derived)
+        .await?
+        .into_response(&req))
+}
+
+#[derive(serde::Deserialize)]
+struct ConcatQuery {
+    /// `/audio` paths joined by `|`
+    parts: String,
+}
+
+/// joins the parts of a split compound into one clip, see `compound_clip`
+async fn serve_concat(req: HttpRequest) -> actix_web::Result<HttpResponse> {
+    let pi = PROGRAM_INFO.get().unwrap();
+    let query = web::Query::<ConcatQuery>::from_query(req.query_string())?.into_inner();
+    let params = web::Query::<DeriveParams>::from_query(req.query_string())?.into_inner();
+    let mut files = Vec::new();
+    for part in query.parts.split('|') {
+        let source = part.split('/').next().unwrap_or_default();
+        let root = pi
+            .roots
+            .get(&AudioSource::from_str(source).unwrap_or_default());
+        let Some(file) = derived::safe_join(root, part) else {
+            return Ok(HttpResponse::BadRequest().body("invalid audio path"));
+        };
+        let hash = pi.meta.file_hash(&file).await.map_err(Error::other)?;
+        files.push((file, hash));
+    }
+
+    let cache = pi.cli.cache.clone();
+    let joined = web::block(move || {
+        let (joined, hash) = derived::concat(&files, &cache)?;
+        derived::derive(&joined, &hash, &params, &cache)
+    })
+    .await??;
+    Ok(actix_files::NamedFile::open_async(joined)
+        .await?
+        .into_response(&req))
+}
+
+/// serves a file from a source packed into a blob database, processed like `serve_audio`
+async fn serve_blob(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> actix_web::Result<HttpResponse> {
+    let pi = PROGRAM_INFO.get().unwrap();
+    let params = web::Query::<DeriveParams>::from_query(req.query_string())?.into_inner();
+    let (source, path) = path.into_inner();
+    let Some(pack) = AudioSource::from_str(&source)
+        .ok()
+        .and_then(|s| pi.blobs.get(&s))
+    else {
+        return Ok(HttpResponse::NotFound().body(format!("{source} is not a packed source")));
+    };
+    let data = match pack.get(&path).await {
+        Ok(Some(data)) => data,
+        Ok(None) => return Ok(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            eprint_pretty!(e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+    if params.for_file(Path::new(&path)).is_empty() {
+        return Ok(helper::serve_bytes(&req, &path, data));
+    }
+
+    let cache = pi.cli.cache.clone();
+    let derived =
+        web::block(move || derived::derive_bytes(&path, &data, &params, &cache)).await??;
+    Ok(actix_files::NamedFile::open_async(derived)
+        .await?
+        .into_response(&req))
+}
+
 #[cfg(target_os = "windows")]
 enum Message {
     Quit,
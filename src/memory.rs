@@ -0,0 +1,308 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use sqlx::sqlite::SqlitePool;
+
+use crate::database::{AudioSource, DatabaseEntry, SortOrder};
+use crate::helper::to_hiragana;
+use crate::search::SearchMode;
+
+/// a row with its reading in hiragana to compare against
+type Row = (Option<String>, DatabaseEntry);
+
+/// the whole entries table loaded at startup (`--memory`), so a lookup is a hash probe
+/// instead of two sql queries and a sort. only for the shipped database, which is read-only.
+pub struct MemoryIndex {
+    /// expression -> its rows, already in the order they are served in
+    by_expression: HashMap<String, Box<[Row]>>,
+    /// every expression, sorted for prefix searches
+    expressions: Box<[String]>,
+    /// hiragana reading -> every expression read that way, with the reading as stored
+    by_reading: HashMap<String, Vec<(String, String)>>,
+    /// expression -> its distinct readings, in table order
+    readings: HashMap<String, Vec<String>>,
+    len: usize,
+}
+
+impl MemoryIndex {
+    pub async fn load(pool: &SqlitePool, sort: &SortOrder) -> Result<Self, sqlx::Error> {
+        let entries: Vec<DatabaseEntry> = sqlx::query_as("SELECT * FROM entries ORDER BY id")
+            .fetch_all(pool)
+            .await?;
+        let len = entries.len();
+
+        let mut by_reading: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        let mut readings: HashMap<String, Vec<String>> = HashMap::new();
+        let mut seen: HashSet<(String, &str)> = HashSet::new();
+        for entry in &entries {
+            if let Some(reading) = &entry.reading {
+                let known = readings.entry(entry.expression.clone()).or_default();
+                if !known.contains(reading) {
+                    known.push(reading.clone());
+                }
+                let hiragana = to_hiragana(reading);
+                if seen.insert((hiragana.clone(), &entry.expression)) {
+                    by_reading
+                        .entry(hiragana)
+                        .or_default()
+                        .push((entry.expression.clone(), reading.clone()));
+                }
+            }
+        }
+        let mut buckets: HashMap<String, Vec<Row>> = HashMap::new();
+        for mut entry in entries {
+            entry.strip_folder_name_prefix();
+            let reading = entry.reading.as_deref().map(to_hiragana);
+            buckets
+                .entry(entry.expression.clone())
+                .or_default()
+                .push((reading, entry));
+        }
+        let mut expressions: Vec<String> = buckets.keys().cloned().collect();
+        expressions.sort_unstable();
+        let by_expression = buckets
+            .into_iter()
+            .map(|(expression, mut rows)| {
+                // stable, so dictionary rows keep their table order within a source
+                rows.sort_by(|(_, a), (_, b)| served_order(sort, a, b));
+                (expression, rows.into_boxed_slice())
+            })
+            .collect();
+        Ok(Self {
+            by_expression,
+            expressions: expressions.into_boxed_slice(),
+            by_reading,
+            readings,
+            len,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// the distinct readings of an expression, in table order
+    pub fn readings(&self, expression: &str) -> Vec<String> {
+        self.readings.get(expression).cloned().unwrap_or_default()
+    }
+
+    pub fn contains(&self, expression: &str) -> bool {
+        self.by_expression.contains_key(expression)
+    }
+
+    /// the same rows as the sql path: dictionary entries with a matching reading
+    /// plus every `forvo_source` entry of the expression
+    pub fn lookup(
+        &self,
+        term: &str,
+        reading: &str,
+        forvo_source: AudioSource,
+    ) -> Vec<DatabaseEntry> {
+        let Some(rows) = self.by_expression.get(term) else {
+            return Vec::new();
+        };
+        let reading = to_hiragana(reading);
+        rows.iter()
+            .filter(|(r, e)| e.source == forvo_source || r.as_deref() == Some(reading.as_str()))
+            .map(|(_, e)| e.clone())
+            .collect()
+    }
+
+    /// every expression with this reading, in table order
+    pub fn expressions_by_reading(&self, reading: &str) -> Vec<(String, String)> {
+        self.by_reading
+            .get(&to_hiragana(reading))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// the same entries as the sql search, ordered by expression
+    pub fn search(&self, q: &str, mode: SearchMode) -> Vec<DatabaseEntry> {
+        let expressions: &[String] = match mode {
+            SearchMode::Prefix => {
+                let start = self.expressions.partition_point(|e| e.as_str() < q);
+                let len = self.expressions[start..]
+                    .iter()
+                    .take_while(|e| e.starts_with(q))
+                    .count();
+                &self.expressions[start..start + len]
+            }
+            _ => &self.expressions,
+        };
+        let reading = to_hiragana(q);
+
+        let mut entries = Vec::new();
+        for expression in expressions {
+            if mode == SearchMode::Substring && !expression.contains(q) {
+                continue;
+            }
+            entries.extend(
+                self.by_expression[expression]
+                    .iter()
+                    .filter(|(r, _)| {
+                        mode != SearchMode::Reading
+                            || r.as_ref().is_some_and(|r| r.starts_with(&reading))
+                    })
+                    .map(|(_, e)| e.clone()),
+            );
+        }
+        entries
+    }
+}
+
+/// sorts by the position of the source (or speaker) in `sort`,
+/// forvo speakers descending like the sql query
+fn served_order(sort: &SortOrder, a: &DatabaseEntry, b: &DatabaseEntry) -> Ordering {
+    sort.position(a).cmp(&sort.position(b)).then_with(|| {
+        if a.source == b.source && a.source.is_forvo() {
+            b.speaker.cmp(&a.speaker)
+        } else {
+            Ordering::Equal
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use super::MemoryIndex;
+    use crate::database::{self, AudioSource};
+    use crate::migrate;
+    use pretty_assertions::assert_eq;
+    use sqlx::sqlite::SqlitePool;
+    use std::sync::OnceLock;
+    use test::Bencher;
+
+    const SORT: [AudioSource; 3] = [
+        AudioSource::ForvoJp,
+        AudioSource::Nhk16,
+        AudioSource::Daijisen,
+    ];
+    const EXPRESSIONS: usize = 20_000;
+
+    fn runtime() -> &'static tokio::runtime::Runtime {
+        static RT: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+        RT.get_or_init(|| tokio::runtime::Runtime::new().unwrap())
+    }
+
+    /// a synthetic entries.db with two dictionary rows & two forvo speakers per expression
+    fn fixture() -> &'static (SqlitePool, MemoryIndex) {
+        static FIXTURE: OnceLock<(SqlitePool, MemoryIndex)> = OnceLock::new();
+        FIXTURE.get_or_init(|| {
+            runtime().block_on(async {
+                let path = std::env::temp_dir().join("yas_memory_fixture.db");
+                let _ = std::fs::remove_file(&path);
+                std::fs::File::create(&path).unwrap();
+                let pool = database::connect(&path, true).await.unwrap();
+                migrate::run(&pool).await.unwrap();
+
+                let mut transaction = pool.begin().await.unwrap();
+                for i in 0..EXPRESSIONS {
+                    for (source, speaker, reading) in [
+                        ("daijisen", None, Some("よみ")),
+                        ("nhk16", None, Some("ヨミ")),
+                        ("forvo_jp", Some("spk1"), None),
+                        ("forvo_jp", Some("spk2"), None),
+                    ] {
+                        sqlx::query("INSERT INTO entries (expression, reading, source, speaker, display, file) VALUES (?, ?, ?, ?, '', ?)")
+                            .bind(format!("語{i}"))
+                            .bind(reading)
+                            .bind(source)
+                            .bind(speaker)
+                            .bind(format!("{source}/{i}.mp3"))
+                            .execute(&mut *transaction)
+                            .await
+                            .unwrap();
+                    }
+                }
+                transaction.commit().await.unwrap();
+                let sort = database::SortOrder::from_sources(&SORT);
+                let index = MemoryIndex::load(&pool, &sort).await.unwrap();
+                (pool, index)
+            })
+        })
+    }
+
+    fn describe(entries: Vec<database::DatabaseEntry>) -> Vec<String> {
+        entries
+            .into_iter()
+            .map(|e| format!("{} {}", e.source, e.file))
+            .collect()
+    }
+
+    #[test]
+    fn lookup_order() {
+        let (_, index) = fixture();
+        assert_eq!(index.len(), EXPRESSIONS * 4);
+        assert_eq!(
+            describe(index.lookup("語7", "よみ", AudioSource::ForvoJp)),
+            [
+                "forvo_jp 7.mp3",
+                "forvo_jp 7.mp3",
+                "nhk16 7.mp3",
+                "daijisen 7.mp3"
+            ]
+        );
+        let speakers: Vec<_> = index
+            .lookup("語7", "よみ", AudioSource::ForvoJp)
+            .into_iter()
+            .filter_map(|e| e.speaker)
+            .collect();
+        assert_eq!(speakers, ["spk2", "spk1"]);
+        // katakana readings match their hiragana rows
+        assert_eq!(index.lookup("語7", "ヨミ", AudioSource::ForvoZh).len(), 2);
+        assert!(index.lookup("語", "よみ", AudioSource::ForvoJp).is_empty());
+        // either spelling finds the expression once, with the first reading stored for it
+        let by_reading = index.expressions_by_reading("ヨミ");
+        assert_eq!(by_reading.len(), EXPRESSIONS);
+        assert_eq!(
+            by_reading[..2],
+            [
+                ("語0".to_string(), "よみ".to_string()),
+                ("語1".to_string(), "よみ".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn readings_match_sql() {
+        let (pool, index) = fixture();
+        // nhk16 (ヨミ) is served before daijisen (よみ), but the readings keep the table order
+        let sql = runtime()
+            .block_on(database::query_readings(pool, "語7"))
+            .unwrap();
+        assert_eq!(sql, ["よみ", "ヨミ"]);
+        assert_eq!(index.readings("語7"), sql);
+        assert!(index.readings("語").is_empty());
+    }
+
+    #[bench]
+    fn bench_memory_lookup(b: &mut Bencher) {
+        let (_, index) = fixture();
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) % EXPRESSIONS;
+            index.lookup(&format!("語{i}"), "よみ", AudioSource::ForvoJp)
+        });
+    }
+
+    #[bench]
+    fn bench_sqlite_lookup(b: &mut Bencher) {
+        let (pool, _) = fixture();
+        let mut i = 0;
+        b.iter(|| {
+            i = (i + 7919) % EXPRESSIONS;
+            let mut entries = runtime()
+                .block_on(database::query_pool(
+                    pool,
+                    &format!("語{i}"),
+                    "よみ",
+                    "forvo_jp",
+                ))
+                .unwrap();
+            entries.sort_by_key(|e| SORT.iter().position(|s| *s == e.source));
+            entries
+        });
+    }
+}
@@ -0,0 +1,464 @@
+use std::fs::{create_dir_all, read_dir};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+use color_print::{ceprintln, cprintln};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+
+use crate::helper::{AudioResult, AUDIO_FILE_STEMS};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct AudioMeta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<i64>,
+    /// integrated loudness in LUFS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loudness: Option<f64>,
+}
+
+impl AudioMeta {
+    /// the metadata of the `start..end` (ms) cut of the file.
+    /// only the duration changes, the rest is measured on the whole file
+    pub fn for_clip(mut self, start: i64, end: Option<i64>) -> Self {
+        let end = match (end, self.duration_ms) {
+            (Some(end), Some(duration)) => Some(end.min(duration)),
+            (end, duration) => end.or(duration),
+        };
+        self.duration_ms = end.map(|end| (end - start).max(0));
+        self
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetaError {
+    #[error("failed to run {bin} (is ffmpeg installed and on PATH?): {source}")]
+    Spawn {
+        bin: &'static str,
+        source: std::io::Error,
+    },
+    #[error("{bin} failed on {path}: {stderr}")]
+    Failed {
+        bin: &'static str,
+        path: String,
+        stderr: String,
+    },
+    #[error("could not parse ffprobe output: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("meta cache error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+    format: Option<FfprobeFormat>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeStream {
+    codec_name: Option<String>,
+    sample_rate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeFormat {
+    duration: Option<String>,
+}
+
+/// runs ffprobe (format) and ffmpeg's ebur128 filter (loudness) on a single file.
+pub fn probe(path: &Path) -> Result<AudioMeta, MetaError> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "a:0"])
+        .args([
+            "-show_entries",
+            "stream=codec_name,sample_rate:format=duration",
+        ])
+        .args(["-of", "json"])
+        .arg(path)
+        .output()
+        .map_err(|source| MetaError::Spawn {
+            bin: "ffprobe",
+            source,
+        })?;
+    if !output.status.success() {
+        return Err(MetaError::Failed {
+            bin: "ffprobe",
+            path: path.display().to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    let mut meta = parse_ffprobe(&output.stdout)?;
+    meta.loudness = measure_loudness(path)?;
+    Ok(meta)
+}
+
+/// fails early with a readable error instead of once per file.
+pub fn check_ffmpeg() -> Result<(), MetaError> {
+    for bin in ["ffprobe", "ffmpeg"] {
+        Command::new(bin)
+            .arg("-version")
+            .output()
+            .map_err(|source| MetaError::Spawn { bin, source })?;
+    }
+    Ok(())
+}
+
+fn parse_ffprobe(stdout: &[u8]) -> Result<AudioMeta, MetaError> {
+    let probe: FfprobeOutput = serde_json::from_slice(stdout)?;
+    let stream = probe.streams.into_iter().next();
+    let duration_ms = probe
+        .format
+        .and_then(|f| f.duration)
+        .and_then(|d| d.parse::<f64>().ok())
+        .map(|secs| (secs * 1000.0).round() as i64);
+    Ok(AudioMeta {
+        duration_ms,
+        codec: stream.as_ref().and_then(|s| s.codec_name.clone()),
+        sample_rate: stream
+            .as_ref()
+            .and_then(|s| s.sample_rate.as_deref())
+            .and_then(|r| r.parse().ok()),
+        loudness: None,
+    })
+}
+
+fn measure_loudness(path: &Path) -> Result<Option<f64>, MetaError> {
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-nostats", "-i"])
+        .arg(path)
+        .args(["-af", "ebur128=framelog=quiet", "-f", "null", "-"])
+        .output()
+        .map_err(|source| MetaError::Spawn {
+            bin: "ffmpeg",
+            source,
+        })?;
+    if !output.status.success() {
+        return Err(MetaError::Failed {
+            bin: "ffmpeg",
+            path: path.display().to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(parse_integrated_loudness(&String::from_utf8_lossy(
+        &output.stderr,
+    )))
+}
+
+/// the ebur128 summary is printed last, so the last `I: <x> LUFS` line wins.
+pub(crate) fn parse_integrated_loudness(stderr: &str) -> Option<f64> {
+    stderr
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("I:"))
+        .filter(|l| l.trim_end().ends_with("LUFS"))
+        .filter_map(|l| l.split_whitespace().next()?.parse::<f64>().ok())
+        .next_back()
+}
+
+/// (mtime, size) of a file. cached metadata is only trusted while this matches.
+fn file_stamp(path: &Path) -> Result<(i64, i64), MetaError> {
+    let md = std::fs::metadata(path)?;
+    let mtime = md
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    Ok((mtime, md.len() as i64))
+}
+
+pub struct MetaCache {
+    pool: SqlitePool,
+}
+
+impl MetaCache {
+    pub async fn open(cache_dir: &Path) -> Result<Self, MetaError> {
+        create_dir_all(cache_dir)?;
+        let opts = SqliteConnectOptions::new()
+            .filename(cache_dir.join("meta.db"))
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(opts).await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS audio_meta
+            (
+                path TEXT PRIMARY KEY,
+                mtime INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                duration_ms INTEGER,
+                codec TEXT,
+                sample_rate INTEGER,
+                loudness REAL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS file_hash
+            (
+                path TEXT PRIMARY KEY,
+                mtime INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                hash TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(Self { pool })
+    }
+
+    /// the content hash derived files of `path` are cached under.
+    /// the file is only read & hashed again when its stamp changes
+    pub async fn file_hash(&self, path: &Path) -> Result<String, MetaError> {
+        let (mtime, size) = file_stamp(path)?;
+        let cached: Option<String> = sqlx::query_scalar(
+            "SELECT hash FROM file_hash WHERE path = ? AND mtime = ? AND size = ?",
+        )
+        .bind(path.to_string_lossy())
+        .bind(mtime)
+        .bind(size)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(hash) = cached {
+            return Ok(hash);
+        }
+        let p = path.to_path_buf();
+        let hash = tokio::task::spawn_blocking(move || crate::derived::hash_file(&p))
+            .await
+            .map_err(|e| MetaError::Io(std::io::Error::other(e)))??;
+        sqlx::query(
+            "INSERT OR REPLACE INTO file_hash (path, mtime, size, hash) VALUES (?, ?, ?, ?)",
+        )
+        .bind(path.to_string_lossy())
+        .bind(mtime)
+        .bind(size)
+        .bind(&hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(hash)
+    }
+
+    pub async fn get(&self, path: &Path) -> Result<Option<AudioMeta>, MetaError> {
+        let (mtime, size) = file_stamp(path)?;
+        let meta = sqlx::query_as::<_, AudioMeta>(
+            "SELECT duration_ms, codec, sample_rate, loudness FROM audio_meta
+            WHERE path = ? AND mtime = ? AND size = ?",
+        )
+        .bind(path.to_string_lossy())
+        .bind(mtime)
+        .bind(size)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(meta)
+    }
+
+    pub async fn insert(&self, path: &Path, meta: &AudioMeta) -> Result<(), MetaError> {
+        let (mtime, size) = file_stamp(path)?;
+        sqlx::query(
+            "INSERT OR REPLACE INTO audio_meta
+            (path, mtime, size, duration_ms, codec, sample_rate, loudness)
+            VALUES
+            (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(path.to_string_lossy())
+        .bind(mtime)
+        .bind(size)
+        .bind(meta.duration_ms)
+        .bind(&meta.codec)
+        .bind(meta.sample_rate)
+        .bind(meta.loudness)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_or_probe(&self, path: &Path) -> Result<AudioMeta, MetaError> {
+        if let Some(meta) = self.get(path).await? {
+            return Ok(meta);
+        }
+        let p = path.to_path_buf();
+        let meta = tokio::task::spawn_blocking(move || probe(&p))
+            .await
+            .map_err(|e| MetaError::Io(std::io::Error::other(e)))??;
+        self.insert(path, &meta).await?;
+        Ok(meta)
+    }
+
+    /// fills in `meta` for every result backed by a local file.
+    /// with `probe` set, files missing from the cache are probed (slow), otherwise they are skipped.
+    pub async fn attach(&self, results: &mut [AudioResult], probe: bool) {
+        for res in results.iter_mut() {
+            let Some(path) = res.path.clone() else {
+                continue;
+            };
+            let meta = if probe {
+                self.get_or_probe(&path).await.map(Some)
+            } else {
+                self.get(&path).await
+            };
+            match meta {
+                Ok(meta) => {
+                    res.meta = match res.clip {
+                        Some((start, end)) => meta.map(|m| m.for_clip(start, end)),
+                        None => meta,
+                    }
+                }
+                Err(e) => tracing::warn!("no metadata for {}: {e}", path.display()),
+            }
+        }
+    }
+
+    /// probes every audio file under `dir` and stores the results.
+    pub async fn scan(&self, dir: &Path, force: bool) -> Result<(), MetaError> {
+        check_ffmpeg()?;
+        let mut files = Vec::new();
+        collect_audio_files(dir, &mut files)?;
+        let total = files.len();
+        if !force {
+            let mut uncached = Vec::with_capacity(files.len());
+            for f in files {
+                if self.get(&f).await?.is_none() {
+                    uncached.push(f);
+                }
+            }
+            files = uncached;
+        }
+        cprintln!(
+            "<g>+</> probing <b>{}</> of {total} audio files in {}",
+            files.len(),
+            dir.display()
+        );
+
+        let probed: Vec<(PathBuf, Result<AudioMeta, MetaError>)> =
+            tokio::task::spawn_blocking(move || {
+                files
+                    .into_par_iter()
+                    .map(|f| {
+                        let m = probe(&f);
+                        (f, m)
+                    })
+                    .collect()
+            })
+            .await
+            .map_err(|e| MetaError::Io(std::io::Error::other(e)))?;
+
+        let mut failed = 0usize;
+        for (path, meta) in &probed {
+            match meta {
+                Ok(meta) => self.insert(path, meta).await?,
+                Err(e) => {
+                    failed += 1;
+                    ceprintln!("<r>[error]</> {e}");
+                }
+            }
+        }
+        cprintln!(
+            "<g>+</> cached metadata for <b>{}</> files (<r>{failed}</> failed)",
+            probed.len() - failed
+        );
+        Ok(())
+    }
+}
+
+pub(crate) fn collect_audio_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for item in read_dir(dir)?.flatten() {
+        let path = item.path();
+        if path.is_dir() {
+            collect_audio_files(&path, files)?;
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| AUDIO_FILE_STEMS.contains(e))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_ffprobe, parse_integrated_loudness, AudioMeta, MetaCache};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn integrated_loudness() {
+        let stderr = "
+[Parsed_ebur128_0 @ 0x600001c1c000] t: 0.299979   TARGET:-23 LUFS    M: -24.1 S:-120.7     I: -24.1 LUFS       LRA:   0.0 LU
+[Parsed_ebur128_0 @ 0x600001c1c000] Summary:
+
+  Integrated loudness:
+    I:         -19.9 LUFS
+    Threshold: -30.0 LUFS
+
+  Loudness range:
+    LRA:         0.0 LU
+";
+        assert_eq!(parse_integrated_loudness(stderr), Some(-19.9));
+        assert_eq!(parse_integrated_loudness("no summary"), None);
+    }
+
+    #[test]
+    fn ffprobe_json() {
+        let out = br#"{
+            "programs": [],
+            "streams": [{ "codec_name": "mp3", "sample_rate": "44100" }],
+            "format": { "duration": "1.253875" }
+        }"#;
+        let meta = parse_ffprobe(out).unwrap();
+        assert_eq!(meta.codec.as_deref(), Some("mp3"));
+        assert_eq!(meta.sample_rate, Some(44100));
+        assert_eq!(meta.duration_ms, Some(1254));
+    }
+
+    #[test]
+    fn clip_duration() {
+        let meta = AudioMeta {
+            duration_ms: Some(60_000),
+            loudness: Some(-20.0),
+            ..Default::default()
+        };
+        // a word cut out of a minute long recording
+        let clip = meta.clone().for_clip(12_300, Some(13_050));
+        assert_eq!(clip.duration_ms, Some(750));
+        assert_eq!(clip.loudness, Some(-20.0));
+        // to the end of the recording
+        assert_eq!(meta.clone().for_clip(59_000, None).duration_ms, Some(1000));
+        assert_eq!(meta.for_clip(59_000, Some(61_000)).duration_ms, Some(1000));
+        assert_eq!(
+            AudioMeta::default().for_clip(500, Some(800)).duration_ms,
+            Some(300)
+        );
+        assert_eq!(AudioMeta::default().for_clip(500, None).duration_ms, None);
+    }
+
+    #[tokio::test]
+    async fn file_hash_follows_stamp() {
+        let dir = std::env::temp_dir().join(format!("yas_file_hash_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = MetaCache::open(&dir).await.unwrap();
+        let file = dir.join("本.mp3");
+        std::fs::write(&file, b"hon").unwrap();
+        assert_eq!(
+            cache.file_hash(&file).await.unwrap(),
+            "0c11c4053dbf3c589550b1cde7fecf1f"
+        );
+        // while the stamp matches the stored hash is used, without reading the file
+        sqlx::query("UPDATE file_hash SET hash = 'stored'")
+            .execute(&cache.pool)
+            .await
+            .unwrap();
+        assert_eq!(cache.file_hash(&file).await.unwrap(), "stored");
+        std::fs::write(&file, b"nihon").unwrap();
+        assert_ne!(cache.file_hash(&file).await.unwrap(), "stored");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
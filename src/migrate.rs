@@ -0,0 +1,306 @@
+use std::path::Path;
+
+use color_print::cprintln;
+use sqlx::sqlite::SqlitePool;
+
+use crate::database::{self, DbError};
+
+/// one schema change of the entries database, applied in a single transaction
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    steps: &'static [Step],
+}
+
+enum Step {
+    Sql(&'static str),
+    /// sqlite has no `ADD COLUMN IF NOT EXISTS`, and some databases already have the column
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        decl: &'static str,
+    },
+}
+
+/// every migration, in order. never edit a released one, append a new version instead.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "baseline entries schema",
+        steps: &[Step::Sql(
+            "CREATE TABLE IF NOT EXISTS entries
+            (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                expression TEXT NOT NULL,
+                reading TEXT,
+                source TEXT NOT NULL,
+                speaker TEXT,
+                display TEXT,
+                file TEXT NOT NULL
+            )",
+        )],
+    },
+    Migration {
+        version: 2,
+        name: "clip offsets",
+        steps: &[
+            Step::AddColumn {
+                table: "entries",
+                column: "start_ms",
+                decl: "INTEGER",
+            },
+            Step::AddColumn {
+                table: "entries",
+                column: "end_ms",
+                decl: "INTEGER",
+            },
+        ],
+    },
+    Migration {
+        version: 3,
+        name: "reading index",
+        steps: &[Step::Sql(
+            "CREATE INDEX IF NOT EXISTS idx_entries_reading ON entries (reading)",
+        )],
+    },
+    Migration {
+        version: 4,
+        name: "expression index",
+        // prefix searches. substring searches use `search_index`, built by the `migrate` command
+        steps: &[Step::Sql(
+            "CREATE INDEX IF NOT EXISTS idx_entries_expression ON entries (expression)",
+        )],
+    },
+];
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// the schema version of the database, 0 if it was never migrated
+pub async fn current_version(pool: &SqlitePool) -> Result<i64, DbError> {
+    let (exists,): (bool,) = sqlx::query_as(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+    )
+    .fetch_one(pool)
+    .await?;
+    if !exists {
+        return Ok(0);
+    }
+    let (version,): (Option<i64>,) = sqlx::query_as("SELECT MAX(version) FROM schema_version")
+        .fetch_one(pool)
+        .await?;
+    Ok(version.unwrap_or(0))
+}
+
+/// applies every pending migration, returns the ones that were applied
+pub async fn run(pool: &SqlitePool) -> Result<Vec<&'static Migration>, DbError> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version
+        (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL DEFAULT (unixepoch())
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    let current = current_version(pool).await?;
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let mut transaction = pool.begin().await?;
+        for step in migration.steps {
+            match step {
+                Step::Sql(sql) => {
+                    sqlx::query(sql).execute(&mut *transaction).await?;
+                }
+                Step::AddColumn {
+                    table,
+                    column,
+                    decl,
+                } => {
+                    let (exists,): (bool,) = sqlx::query_as(
+                        "SELECT COUNT(*) > 0 FROM pragma_table_info(?) WHERE name = ?",
+                    )
+                    .bind(table)
+                    .bind(column)
+                    .fetch_one(&mut *transaction)
+                    .await?;
+                    if !exists {
+                        sqlx::query(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"))
+                            .execute(&mut *transaction)
+                            .await?;
+                    }
+                }
+            }
+        }
+        sqlx::query("INSERT INTO schema_version (version, name) VALUES (?, ?)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(&mut *transaction)
+            .await?;
+        // mirrored into the header, where `extract_embedded_db` reads it without opening the db
+        sqlx::query(&format!("PRAGMA user_version = {}", migration.version))
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        applied.push(migration);
+    }
+    Ok(applied)
+}
+
+/// the trigram index of substring searches, kept in sync with `entries` by triggers.
+/// building it takes a while on the full database, so it isn't part of the startup migrations
+static SEARCH_INDEX: &[&str] = &[
+    "CREATE VIRTUAL TABLE entries_fts USING fts5
+    (expression, content = 'entries', content_rowid = 'id', tokenize = 'trigram')",
+    "INSERT INTO entries_fts (entries_fts) VALUES ('rebuild')",
+    "CREATE TRIGGER entries_fts_insert AFTER INSERT ON entries BEGIN
+        INSERT INTO entries_fts (rowid, expression) VALUES (new.id, new.expression);
+    END",
+    "CREATE TRIGGER entries_fts_delete AFTER DELETE ON entries BEGIN
+        INSERT INTO entries_fts (entries_fts, rowid, expression)
+            VALUES ('delete', old.id, old.expression);
+    END",
+    "CREATE TRIGGER entries_fts_update AFTER UPDATE OF expression ON entries BEGIN
+        INSERT INTO entries_fts (entries_fts, rowid, expression)
+            VALUES ('delete', old.id, old.expression);
+        INSERT INTO entries_fts (rowid, expression) VALUES (new.id, new.expression);
+    END",
+];
+
+pub async fn has_search_index(pool: &SqlitePool) -> Result<bool, DbError> {
+    let (exists,): (bool,) = sqlx::query_as(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'entries_fts'",
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(exists)
+}
+
+/// builds the substring search index if the database doesn't have it yet, returns whether it did
+pub async fn build_search_index(pool: &SqlitePool) -> Result<bool, DbError> {
+    if has_search_index(pool).await? {
+        return Ok(false);
+    }
+    let mut transaction = pool.begin().await?;
+    for sql in SEARCH_INDEX {
+        sqlx::query(sql).execute(&mut *transaction).await?;
+    }
+    transaction.commit().await?;
+    Ok(true)
+}
+
+/// brings the database at `path` up to date at startup.
+/// it is only opened writable when it is actually behind.
+pub async fn upgrade(path: &Path) -> Result<(), DbError> {
+    let pool = database::connect(path, false).await?;
+    let current = current_version(&pool).await?;
+    pool.close().await;
+    if current >= latest_version() {
+        return Ok(());
+    }
+
+    let pool = database::connect(path, true).await?;
+    let res = run(&pool).await;
+    pool.close().await;
+    for migration in res? {
+        cprintln!(
+            "<i><g>+</> migrated {} to v{} ({})</>",
+            path.display(),
+            migration.version,
+            migration.name
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current_version, latest_version, run, upgrade, MIGRATIONS};
+    use crate::database::{self, DatabaseEntry};
+    use pretty_assertions::assert_eq;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn upgrade_fixture() {
+        let path = std::env::temp_dir().join("yas_upgrade_fixture.db");
+        let _ = std::fs::remove_file(&path);
+        std::fs::File::create(&path).unwrap();
+
+        // the schema entries.db shipped with before it was versioned
+        let pool = database::connect(&path, true).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE entries
+            (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                expression TEXT NOT NULL,
+                reading TEXT,
+                source TEXT NOT NULL,
+                speaker TEXT,
+                display TEXT,
+                file TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO entries (expression, reading, source, display, file) VALUES ('本', 'ほん', 'nhk16', '', '本.mp3')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(current_version(&pool).await.unwrap(), 0);
+        pool.close().await;
+
+        upgrade(&path).await.unwrap();
+
+        let pool = database::connect(&path, true).await.unwrap();
+        assert_eq!(current_version(&pool).await.unwrap(), latest_version());
+        let (user_version,): (i64,) = sqlx::query_as("PRAGMA user_version")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(user_version, latest_version());
+        let entry: DatabaseEntry = sqlx::query_as("SELECT * FROM entries")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(entry.file, "本.mp3");
+        assert_eq!(entry.start_ms, None);
+        // running again is a no-op
+        assert_eq!(run(&pool).await.unwrap().len(), 0);
+        pool.close().await;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn existing_columns() {
+        // databases built by `create_test_table` already have the clip offsets.
+        // one connection, since every in-memory connection is its own database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::query(
+            "CREATE TABLE entries
+            (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                expression TEXT NOT NULL,
+                reading TEXT,
+                source TEXT NOT NULL,
+                speaker TEXT,
+                display TEXT,
+                file TEXT NOT NULL,
+                start_ms INTEGER,
+                end_ms INTEGER
+            )",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert_eq!(run(&pool).await.unwrap().len(), MIGRATIONS.len());
+        assert_eq!(current_version(&pool).await.unwrap(), latest_version());
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+
+use crate::database::{DatabaseEntry, DbError};
+use crate::helper::{to_hiragana, to_katakana};
+use crate::memory::MemoryIndex;
+use crate::migrate;
+use crate::user::{self, Precedence, UserDb};
+
+const DEFAULT_PER_PAGE: u32 = 50;
+const MAX_PER_PAGE: u32 = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// expressions starting with the query
+    #[default]
+    Prefix,
+    /// expressions containing the query
+    Substring,
+    /// readings starting with the query, hiragana & katakana alike
+    Reading,
+}
+
+/// `/search?q=日本&mode=prefix&page=1&per_page=50`
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    #[serde(default)]
+    pub mode: SearchMode,
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
+
+impl SearchQuery {
+    pub fn paging(&self) -> Paging {
+        Paging {
+            page: self.page,
+            per_page: self.per_page,
+        }
+    }
+}
+
+/// `page` & `per_page` of the routes that return a list
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Paging {
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
+
+impl Paging {
+    /// 1 based
+    pub fn page(&self) -> u32 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    pub fn per_page(&self) -> u32 {
+        self.per_page
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE)
+    }
+
+    /// saturates, `page` has no upper bound
+    pub fn offset(&self) -> usize {
+        let offset = u64::from(self.page() - 1).saturating_mul(u64::from(self.per_page()));
+        usize::try_from(offset).unwrap_or(usize::MAX)
+    }
+}
+
+/// every (expression, reading, source) that has audio, with how many files it has
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchHit {
+    pub expression: String,
+    pub reading: Option<String>,
+    pub source: String,
+    pub count: i64,
+}
+
+pub struct SearchPage {
+    /// hits on every page
+    pub total: usize,
+    pub hits: Vec<SearchHit>,
+}
+
+/// `*`, `?` & `[` in the query are matched literally
+fn escape_glob(q: &str) -> String {
+    q.chars()
+        .map(|c| match c {
+            '*' | '?' | '[' => format!("[{c}]"),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// the `WHERE` clause matching the query, and the patterns to bind to it.
+/// substring searches use the trigram index where the database has one (see `migrate`)
+fn where_clause(query: &SearchQuery, fts: bool) -> (&'static str, Vec<String>) {
+    let q = escape_glob(&query.q);
+    match query.mode {
+        SearchMode::Prefix => ("expression GLOB ?", vec![format!("{q}*")]),
+        SearchMode::Substring if fts => (
+            "id IN (SELECT rowid FROM entries_fts WHERE expression GLOB ?)",
+            vec![format!("*{q}*")],
+        ),
+        SearchMode::Substring => ("expression GLOB ?", vec![format!("*{q}*")]),
+        SearchMode::Reading => (
+            "(reading GLOB ? OR reading GLOB ?)",
+            vec![
+                format!("{}*", to_hiragana(&q)),
+                format!("{}*", to_katakana(&q)),
+            ],
+        ),
+    }
+}
+
+/// the rows of `pool` matching `filter` and `and`, with the folder names stripped like in lookups
+async fn matching_entries(
+    pool: &SqlitePool,
+    filter: &str,
+    patterns: &[String],
+    and: Option<(&str, &str)>,
+) -> Result<Vec<DatabaseEntry>, DbError> {
+    let sql = match and {
+        Some((clause, _)) => format!("SELECT * FROM entries WHERE ({filter}) AND {clause}"),
+        None => format!("SELECT * FROM entries WHERE {filter}"),
+    };
+    let mut entries = sqlx::query_as::<_, DatabaseEntry>(&sql);
+    for p in patterns {
+        entries = entries.bind(p);
+    }
+    if let Some((_, value)) = and {
+        entries = entries.bind(value);
+    }
+    let mut entries = entries.fetch_all(pool).await?;
+    entries
+        .iter_mut()
+        .for_each(DatabaseEntry::strip_folder_name_prefix);
+    Ok(entries)
+}
+
+/// the hits of `entries`, ordered by expression, reading & source
+fn group(entries: Vec<DatabaseEntry>) -> Vec<SearchHit> {
+    let mut groups: BTreeMap<(String, Option<String>, String), i64> = BTreeMap::new();
+    for e in entries {
+        *groups
+            .entry((e.expression, e.reading, e.source.to_string()))
+            .or_default() += 1;
+    }
+    groups
+        .into_iter()
+        .map(|((expression, reading, source), count)| SearchHit {
+            expression,
+            reading,
+            source,
+            count,
+        })
+        .collect()
+}
+
+/// the number of groups `from` matches, only the ones of expressions before `before` if set
+async fn count_groups(
+    pool: &SqlitePool,
+    from: &str,
+    patterns: &[String],
+    expressions: &str,
+    before: Option<&str>,
+) -> Result<usize, DbError> {
+    let and = if before.is_some() {
+        "AND expression < ?"
+    } else {
+        ""
+    };
+    let sql = format!(
+        "SELECT COUNT(*) FROM (SELECT 1 {from} {and} GROUP BY expression, reading, source)"
+    );
+    let mut count = sqlx::query_scalar::<_, i64>(&sql);
+    for p in patterns {
+        count = count.bind(p);
+    }
+    count = count.bind(expressions);
+    if let Some(before) = before {
+        count = count.bind(before);
+    }
+    Ok(count.fetch_one(pool).await? as usize)
+}
+
+pub async fn search(
+    pool: &SqlitePool,
+    user_db: &UserDb,
+    memory: Option<&MemoryIndex>,
+    query: &SearchQuery,
+    precedence: Precedence,
+) -> Result<SearchPage, DbError> {
+    // hidden rows hide shipped rows whatever they match
+    let (filter, patterns) = where_clause(query, false);
+    let user_filter = format!("hidden = 1 OR {filter}");
+    let user = matching_entries(user_db.pool(), &user_filter, &patterns, None).await?;
+
+    if let Some(memory) = memory {
+        let shipped = memory.search(&query.q, query.mode);
+        let hits = group(user::overlay(shipped, user, precedence));
+        return Ok(SearchPage {
+            total: hits.len(),
+            hits: hits
+                .into_iter()
+                .skip(query.paging().offset())
+                .take(query.paging().per_page() as usize)
+                .collect(),
+        });
+    }
+
+    // the groups of the expressions the user db has rows for are overlaid here,
+    // every other group is counted & paginated by sqlite
+    let mut expressions: Vec<&str> = user.iter().map(|e| e.expression.as_str()).collect();
+    expressions.sort_unstable();
+    expressions.dedup();
+    let expressions = serde_json::to_string(&expressions).unwrap_or_default();
+    let (filter, patterns) = where_clause(query, migrate::has_search_index(pool).await?);
+    let touched = matching_entries(
+        pool,
+        filter,
+        &patterns,
+        Some((
+            "expression IN (SELECT value FROM json_each(?))",
+            &expressions,
+        )),
+    )
+    .await?;
+    let touched = group(user::overlay(touched, user, precedence));
+
+    let untouched = format!(
+        "FROM entries WHERE ({filter}) AND expression NOT IN (SELECT value FROM json_each(?))"
+    );
+    let total =
+        count_groups(pool, &untouched, &patterns, &expressions, None).await? + touched.len();
+
+    // an untouched group's position is its index plus the touched groups before it, so the
+    // ones on this page are at most `touched.len()` before it in the untouched order
+    let paging = query.paging();
+    let (offset, per_page) = (paging.offset(), paging.per_page() as usize);
+    let start = offset.saturating_sub(touched.len());
+    let sql = format!(
+        "SELECT expression, reading, source, COUNT(*) {untouched}
+        GROUP BY expression, reading, source
+        ORDER BY expression, reading, source
+        LIMIT ? OFFSET ?"
+    );
+    let mut rows = sqlx::query_as::<_, (String, Option<String>, String, i64)>(&sql);
+    for p in &patterns {
+        rows = rows.bind(p);
+    }
+    let rows = rows
+        .bind(&expressions)
+        .bind((per_page + touched.len()) as i64)
+        .bind(i64::try_from(start).unwrap_or(i64::MAX))
+        .fetch_all(pool)
+        .await?;
+
+    let mut page: Vec<(usize, SearchHit)> = Vec::new();
+    for (i, (expression, reading, source, count)) in rows.into_iter().enumerate() {
+        let before = touched.partition_point(|t| t.expression < expression);
+        let hit = SearchHit {
+            expression,
+            reading,
+            source,
+            count,
+        };
+        page.push((start + i + before, hit));
+    }
+    let mut ranks: HashMap<String, usize> = HashMap::new();
+    for (i, hit) in touched.into_iter().enumerate() {
+        let rank = match ranks.get(&hit.expression) {
+            Some(&rank) => rank,
+            None => {
+                let before = Some(hit.expression.as_str());
+                let rank = count_groups(pool, &untouched, &patterns, &expressions, before).await?;
+                ranks.insert(hit.expression.clone(), rank);
+                rank
+            }
+        };
+        page.push((i + rank, hit));
+    }
+    page.retain(|(pos, _)| (offset..offset.saturating_add(per_page)).contains(pos));
+    page.sort_by_key(|(pos, _)| *pos);
+    Ok(SearchPage {
+        total,
+        hits: page.into_iter().map(|(_, hit)| hit).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{search, SearchMode, SearchQuery};
+    use crate::database::{self, AudioSource};
+    use crate::memory::MemoryIndex;
+    use crate::migrate;
+    use crate::user::{Precedence, UserDb};
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn sql_and_memory_agree() {
+        let path = std::env::temp_dir().join("yas_search.db");
+        let _ = std::fs::remove_file(&path);
+        std::fs::File::create(&path).unwrap();
+        let pool = database::connect(&path, true).await.unwrap();
+        migrate::run(&pool).await.unwrap();
+        for (expression, reading, source, file) in [
+            ("日本", Some("にほん"), "nhk16", "a.mp3"),
+            ("日本", Some("にほん"), "daijisen", "b.mp3"),
+            ("日本", None, "forvo_jp", "c.mp3"),
+            ("日本", None, "forvo_jp", "d.mp3"),
+            ("日本語", Some("ニホンゴ"), "nhk16", "e.mp3"),
+            ("本日", Some("ほんじつ"), "nhk16", "f.mp3"),
+            ("100%", None, "forvo_jp", "g.mp3"),
+        ] {
+            sqlx::query("INSERT INTO entries (expression, reading, source, display, file) VALUES (?, ?, ?, '', ?)")
+                .bind(expression)
+                .bind(reading)
+                .bind(source)
+                .bind(file)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let sort = database::SortOrder::from_sources(&[AudioSource::Nhk16]);
+        let memory = MemoryIndex::load(&pool, &sort).await.unwrap();
+        let user_path =
+            std::env::temp_dir().join(format!("yas_search_user_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&user_path);
+        let user_db = UserDb::open(&user_path).await.unwrap();
+
+        let describe = |hits: Vec<super::SearchHit>| {
+            hits.into_iter()
+                .map(|h| {
+                    format!(
+                        "{} {} {} {}",
+                        h.expression,
+                        h.reading.unwrap_or_default(),
+                        h.source,
+                        h.count
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        // substring searches with and without the trigram index
+        for fts in [false, true] {
+            if fts {
+                assert!(migrate::build_search_index(&pool).await.unwrap());
+            }
+            for (q, mode, expected) in [
+                (
+                    "日本",
+                    SearchMode::Prefix,
+                    vec![
+                        "日本  forvo_jp 2",
+                        "日本 にほん daijisen 1",
+                        "日本 にほん nhk16 1",
+                        "日本語 ニホンゴ nhk16 1",
+                    ],
+                ),
+                (
+                    "本",
+                    SearchMode::Substring,
+                    vec![
+                        "日本  forvo_jp 2",
+                        "日本 にほん daijisen 1",
+                        "日本 にほん nhk16 1",
+                        "日本語 ニホンゴ nhk16 1",
+                        "本日 ほんじつ nhk16 1",
+                    ],
+                ),
+                (
+                    "にほんご",
+                    SearchMode::Reading,
+                    vec!["日本語 ニホンゴ nhk16 1"],
+                ),
+                // long enough for the trigram index
+                (
+                    "日本語",
+                    SearchMode::Substring,
+                    vec!["日本語 ニホンゴ nhk16 1"],
+                ),
+                ("0%", SearchMode::Substring, vec!["100%  forvo_jp 1"]),
+                ("%", SearchMode::Prefix, vec![]),
+                ("*", SearchMode::Prefix, vec![]),
+            ] {
+                let query = SearchQuery {
+                    q: q.to_string(),
+                    mode,
+                    ..Default::default()
+                };
+                let sql = search(&pool, &user_db, None, &query, Precedence::User)
+                    .await
+                    .unwrap();
+                let mem = search(&pool, &user_db, Some(&memory), &query, Precedence::User)
+                    .await
+                    .unwrap();
+                assert_eq!(sql.total, expected.len(), "{q} {fts}");
+                assert_eq!(mem.total, expected.len(), "{q}");
+                assert_eq!(describe(sql.hits), expected, "{q} {fts}");
+                assert_eq!(describe(mem.hits), expected, "{q}");
+            }
+        }
+
+        let query = SearchQuery {
+            q: "日本".to_string(),
+            page: Some(2),
+            per_page: Some(3),
+            ..Default::default()
+        };
+        let page = search(&pool, &user_db, None, &query, Precedence::User)
+            .await
+            .unwrap();
+        assert_eq!(page.total, 4);
+        assert_eq!(describe(page.hits), ["日本語 ニホンゴ nhk16 1"]);
+
+        let query = SearchQuery {
+            q: "日本".to_string(),
+            page: Some(u32::MAX),
+            per_page: Some(500),
+            ..Default::default()
+        };
+        let page = search(&pool, &user_db, None, &query, Precedence::User)
+            .await
+            .unwrap();
+        assert_eq!(page.total, 4);
+        assert!(page.hits.is_empty());
+
+        // a user entry, and a shipped one hidden by the user db
+        for (expression, reading, file, hidden) in [
+            ("日本橋", Some("にほんばし"), "h.mp3", false),
+            ("日本", None, "c.mp3", true),
+        ] {
+            sqlx::query("INSERT INTO entries (expression, reading, source, display, file, hidden) VALUES (?, ?, 'forvo_jp', '', ?, ?)")
+                .bind(expression)
+                .bind(reading)
+                .bind(file)
+                .bind(hidden)
+                .execute(user_db.pool())
+                .await
+                .unwrap();
+        }
+        let expected = [
+            "日本  forvo_jp 1",
+            "日本 にほん daijisen 1",
+            "日本 にほん nhk16 1",
+            "日本橋 にほんばし forvo_jp 1",
+            "日本語 ニホンゴ nhk16 1",
+        ];
+        for mode in [SearchMode::Prefix, SearchMode::Substring] {
+            let query = SearchQuery {
+                q: "日本".to_string(),
+                mode,
+                ..Default::default()
+            };
+            let sql = search(&pool, &user_db, None, &query, Precedence::User)
+                .await
+                .unwrap();
+            let mem = search(&pool, &user_db, Some(&memory), &query, Precedence::User)
+                .await
+                .unwrap();
+            assert_eq!(describe(sql.hits), expected);
+            assert_eq!(describe(mem.hits), expected);
+            // the overlaid groups land on the same pages as when everything is grouped in memory
+            for per_page in 1..=6 {
+                for page in 1..=6 {
+                    let query = SearchQuery {
+                        q: query.q.clone(),
+                        mode,
+                        page: Some(page),
+                        per_page: Some(per_page),
+                    };
+                    let sql = search(&pool, &user_db, None, &query, Precedence::User)
+                        .await
+                        .unwrap();
+                    let mem = search(&pool, &user_db, Some(&memory), &query, Precedence::User)
+                        .await
+                        .unwrap();
+                    assert_eq!(sql.total, expected.len());
+                    assert_eq!(describe(sql.hits), describe(mem.hits), "{page} {per_page}");
+                }
+            }
+        }
+        pool.close().await;
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(user_path).unwrap();
+    }
+}
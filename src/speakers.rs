@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+
+use crate::database::DatabaseEntry;
+
+/// what's known about a speaker, from the `speakers` table of the user database
+#[derive(Debug, Clone, Default, PartialEq, FromRow)]
+pub struct SpeakerInfo {
+    pub name: String,
+    pub gender: Option<String>,
+    /// region or dialect, ex: Tokyo
+    pub region: Option<String>,
+}
+
+/// every field that's set has to match, case insensitive
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeakerFilter {
+    pub gender: Option<String>,
+    /// a part of the region, "tokyo" matches "Tokyo, Japan"
+    pub region: Option<String>,
+}
+
+impl SpeakerFilter {
+    pub fn is_empty(&self) -> bool {
+        self.gender.is_none() && self.region.is_none()
+    }
+
+    fn matches(&self, info: &SpeakerInfo) -> bool {
+        let gender = self.gender.as_ref().is_none_or(|g| {
+            info.gender
+                .as_ref()
+                .is_some_and(|i| i.eq_ignore_ascii_case(g))
+        });
+        let region = self.region.as_ref().is_none_or(|r| {
+            info.region
+                .as_ref()
+                .is_some_and(|i| i.to_lowercase().contains(&r.to_lowercase()))
+        });
+        gender && region
+    }
+}
+
+/// `"speakers"` in config.json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeakerConfig {
+    /// served first, in this order
+    pub preferred: Vec<String>,
+    /// speakers matching it come next
+    pub prefer: SpeakerFilter,
+    /// speakers that don't match it are dropped, the ones without metadata are kept
+    pub only: SpeakerFilter,
+}
+
+impl SpeakerConfig {
+    pub fn is_empty(&self) -> bool {
+        self.preferred.is_empty() && !self.needs_info()
+    }
+
+    /// whether `apply` has to know the speakers' metadata
+    pub fn needs_info(&self) -> bool {
+        !self.prefer.is_empty() || !self.only.is_empty()
+    }
+
+    /// drops & reorders the entries by speaker. stable, so the sort by source can follow
+    pub fn apply(&self, entries: &mut Vec<DatabaseEntry>, info: &HashMap<String, SpeakerInfo>) {
+        let info_of = |e: &DatabaseEntry| e.speaker.as_ref().and_then(|s| info.get(s));
+        if !self.only.is_empty() {
+            entries.retain(|e| info_of(e).is_none_or(|i| self.only.matches(i)));
+        }
+        entries.sort_by_key(|e| {
+            let preferred = e
+                .speaker
+                .as_ref()
+                .and_then(|s| self.preferred.iter().position(|p| p == s))
+                .unwrap_or(self.preferred.len());
+            let prefer =
+                !self.prefer.is_empty() && info_of(e).is_some_and(|i| self.prefer.matches(i));
+            (preferred, !prefer)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SpeakerConfig, SpeakerFilter, SpeakerInfo};
+    use crate::database::{AudioSource, DatabaseEntry};
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    #[test]
+    fn speaker_ranking() {
+        let mut entries: Vec<DatabaseEntry> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|s| DatabaseEntry {
+                source: AudioSource::ForvoJp,
+                speaker: Some(s.to_string()),
+                ..Default::default()
+            })
+            .collect();
+        let info: HashMap<String, SpeakerInfo> = [
+            ("b", "male", "Osaka"),
+            ("c", "female", "Tokyo, Japan"),
+            ("d", "male", "Tokyo"),
+        ]
+        .iter()
+        .map(|(name, gender, region)| {
+            let info = SpeakerInfo {
+                name: name.to_string(),
+                gender: Some(gender.to_string()),
+                region: Some(region.to_string()),
+            };
+            (name.to_string(), info)
+        })
+        .collect();
+        let config = SpeakerConfig {
+            preferred: vec!["e".to_string()],
+            prefer: SpeakerFilter {
+                region: Some("tokyo".to_string()),
+                ..Default::default()
+            },
+            only: SpeakerFilter {
+                gender: Some("Female".to_string()),
+                ..Default::default()
+            },
+        };
+        config.apply(&mut entries, &info);
+        let speakers: Vec<&str> = entries
+            .iter()
+            .map(|e| e.speaker.as_deref().unwrap())
+            .collect();
+        // b & d are male, a & e have no metadata
+        assert_eq!(speakers, ["e", "c", "a"]);
+    }
+}
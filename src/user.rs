@@ -0,0 +1,420 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+
+use crate::database::{AudioSource, DatabaseEntry, DbError};
+use crate::helper::to_hiragana;
+use crate::speakers::SpeakerInfo;
+
+/// which database wins when the shipped & user database both have an entry
+/// with the same expression, source & file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precedence {
+    #[default]
+    User,
+    Shipped,
+}
+
+/// an entry that's always served first for an expression & reading, regardless of `sort.txt`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct Pin {
+    pub expression: String,
+    /// empty pins the entry for every reading of the expression
+    #[serde(default)]
+    pub reading: String,
+    /// as listed by `--sources`
+    pub source: String,
+    /// any speaker when missing
+    pub speaker: Option<String>,
+    pub file: String,
+}
+
+impl Pin {
+    pub fn matches(&self, e: &DatabaseEntry) -> bool {
+        let file = Path::new(&self.file).file_name().unwrap_or_default();
+        e.source.to_string() == self.source
+            && Path::new(&e.file).file_name() == Some(file)
+            && self
+                .speaker
+                .as_ref()
+                .is_none_or(|s| e.speaker.as_ref() == Some(s))
+    }
+}
+
+/// entries that are never served. each field that isn't empty has to match,
+/// and at least one of `speaker` & `file` is set
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, FromRow)]
+#[serde(default)]
+pub struct Block {
+    pub expression: String,
+    pub speaker: String,
+    pub file: String,
+}
+
+impl Block {
+    pub fn is_valid(&self) -> bool {
+        !self.speaker.is_empty() || !self.file.is_empty()
+    }
+
+    pub fn matches(&self, e: &DatabaseEntry) -> bool {
+        (self.expression.is_empty() || e.expression == self.expression)
+            && (self.speaker.is_empty() || e.speaker.as_deref() == Some(self.speaker.as_str()))
+            && (self.file.is_empty()
+                || Path::new(&e.file).file_name() == Path::new(&self.file).file_name())
+    }
+}
+
+impl std::fmt::Display for Block {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fields = [
+            ("expression", &self.expression),
+            ("speaker", &self.speaker),
+            ("file", &self.file),
+        ];
+        let fields: Vec<String> = fields
+            .iter()
+            .filter(|(_, v)| !v.is_empty())
+            .map(|(k, v)| format!("{k} {v}"))
+            .collect();
+        write!(f, "{}", fields.join(", "))
+    }
+}
+
+/// the user owned database, kept apart from the shipped entries.db so it survives upgrades.
+/// its `entries` table has the same columns as the shipped one, plus `hidden`.
+/// the `speakers` table is filled in by the user too, it's only read.
+pub struct UserDb {
+    pool: SqlitePool,
+}
+
+impl UserDb {
+    pub async fn open(path: &Path) -> Result<Self, DbError> {
+        let opts = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(opts).await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS entries
+            (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                expression TEXT NOT NULL,
+                reading TEXT,
+                source TEXT NOT NULL,
+                speaker TEXT,
+                display TEXT,
+                file TEXT NOT NULL,
+                start_ms INTEGER,
+                end_ms INTEGER,
+                hidden INTEGER NOT NULL DEFAULT 0
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_entries_expression ON entries (expression)")
+            .execute(&pool)
+            .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_entries_reading ON entries (reading)")
+            .execute(&pool)
+            .await?;
+        // readings are stored in hiragana
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS pins
+            (
+                expression TEXT NOT NULL,
+                reading TEXT NOT NULL DEFAULT '',
+                source TEXT NOT NULL,
+                speaker TEXT,
+                file TEXT NOT NULL,
+                PRIMARY KEY (expression, reading)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS blocklist
+            (
+                expression TEXT NOT NULL DEFAULT '',
+                speaker TEXT NOT NULL DEFAULT '',
+                file TEXT NOT NULL DEFAULT '',
+                PRIMARY KEY (expression, speaker, file)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS speakers
+            (
+                name TEXT PRIMARY KEY,
+                gender TEXT,
+                region TEXT
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(Self { pool })
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    /// replaces the pin of the same expression & reading
+    pub async fn pin(&self, pin: &Pin) -> Result<(), DbError> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO pins (expression, reading, source, speaker, file)
+            VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&pin.expression)
+        .bind(to_hiragana(&pin.reading))
+        .bind(&pin.source)
+        .bind(&pin.speaker)
+        .bind(&pin.file)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// whether there was a pin to remove
+    pub async fn unpin(&self, expression: &str, reading: &str) -> Result<bool, DbError> {
+        let res = sqlx::query("DELETE FROM pins WHERE expression = ? AND reading = ?")
+            .bind(expression)
+            .bind(to_hiragana(reading))
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    pub async fn pins(&self) -> Result<Vec<Pin>, DbError> {
+        let pins = sqlx::query_as("SELECT * FROM pins ORDER BY expression, reading")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(pins)
+    }
+
+    /// the pin of this reading, else the one of every reading
+    pub async fn pin_for(&self, expression: &str, reading: &str) -> Result<Option<Pin>, DbError> {
+        let pin = sqlx::query_as(
+            "SELECT * FROM pins
+            WHERE expression = ? AND reading IN (?, '')
+            ORDER BY reading DESC
+            LIMIT 1",
+        )
+        .bind(expression)
+        .bind(to_hiragana(reading))
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(pin)
+    }
+
+    /// whether it wasn't blocked already
+    pub async fn block(&self, block: &Block) -> Result<bool, DbError> {
+        let res = sqlx::query(
+            "INSERT OR IGNORE INTO blocklist (expression, speaker, file) VALUES (?, ?, ?)",
+        )
+        .bind(&block.expression)
+        .bind(&block.speaker)
+        .bind(&block.file)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// whether there was a block to remove
+    pub async fn unblock(&self, block: &Block) -> Result<bool, DbError> {
+        let res =
+            sqlx::query("DELETE FROM blocklist WHERE expression = ? AND speaker = ? AND file = ?")
+                .bind(&block.expression)
+                .bind(&block.speaker)
+                .bind(&block.file)
+                .execute(&self.pool)
+                .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// every speaker with metadata, by name
+    pub async fn speaker_info(&self) -> Result<HashMap<String, SpeakerInfo>, DbError> {
+        let info: Vec<SpeakerInfo> = sqlx::query_as("SELECT * FROM speakers")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(info.into_iter().map(|i| (i.name.clone(), i)).collect())
+    }
+
+    /// the blocklist & speaker metadata, read once per request instead of once per lookup
+    pub async fn filters(&self, speaker_info: bool) -> Result<UserFilters, DbError> {
+        Ok(UserFilters {
+            blocklist: self.blocklist().await?,
+            speakers: match speaker_info {
+                true => self.speaker_info().await?,
+                false => HashMap::new(),
+            },
+        })
+    }
+
+    pub async fn blocklist(&self) -> Result<Vec<Block>, DbError> {
+        let blocks = sqlx::query_as("SELECT * FROM blocklist ORDER BY expression, speaker, file")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(blocks)
+    }
+}
+
+/// what the user database filters & ranks the entries of a request with, see `UserDb::filters`
+#[derive(Debug, Default)]
+pub struct UserFilters {
+    pub blocklist: Vec<Block>,
+    /// empty unless the speaker config needs it
+    pub speakers: HashMap<String, SpeakerInfo>,
+}
+
+type EntryKey = (String, AudioSource, String);
+
+fn key(e: &DatabaseEntry) -> EntryKey {
+    (e.expression.clone(), e.source, e.file.clone())
+}
+
+/// merges the user entries over the shipped ones.
+/// hidden user entries remove their shipped counterpart, duplicates are resolved by `precedence`,
+/// and the winning database's entries come first.
+pub fn overlay(
+    shipped: Vec<DatabaseEntry>,
+    user: Vec<DatabaseEntry>,
+    precedence: Precedence,
+) -> Vec<DatabaseEntry> {
+    let hidden: HashSet<EntryKey> = user.iter().filter(|e| e.hidden).map(key).collect();
+    let user: Vec<DatabaseEntry> = user.into_iter().filter(|e| !e.hidden).collect();
+    let shipped: Vec<DatabaseEntry> = shipped
+        .into_iter()
+        .filter(|e| !hidden.contains(&key(e)))
+        .collect();
+
+    let (first, second) = match precedence {
+        Precedence::User => (user, shipped),
+        Precedence::Shipped => (shipped, user),
+    };
+    let taken: HashSet<EntryKey> = first.iter().map(key).collect();
+    let mut entries = first;
+    entries.extend(second.into_iter().filter(|e| !taken.contains(&key(e))));
+    entries
+}
+
+pub fn remove_blocked(entries: &mut Vec<DatabaseEntry>, blocklist: &[Block]) {
+    entries.retain(|e| !blocklist.iter().any(|b| b.matches(e)));
+}
+
+/// moves the pinned entries to the front, keeping the order of the rest
+pub fn apply_pin(entries: &mut [DatabaseEntry], pin: &Pin) {
+    entries.sort_by_key(|e| !pin.matches(e));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_pin, overlay, remove_blocked, Block, Pin, Precedence};
+    use crate::database::{AudioSource, DatabaseEntry};
+    use pretty_assertions::assert_eq;
+
+    fn entry(source: AudioSource, file: &str, display: &str) -> DatabaseEntry {
+        DatabaseEntry {
+            expression: "本".to_string(),
+            source,
+            display: display.to_string(),
+            file: file.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn overlay_precedence() {
+        let shipped = vec![
+            entry(AudioSource::Nhk16, "a.mp3", "shipped"),
+            entry(AudioSource::Jpod, "b.mp3", "shipped"),
+            entry(AudioSource::ForvoJp, "c.mp3", "shipped"),
+        ];
+        let user = vec![
+            entry(AudioSource::Nhk16, "a.mp3", "user"),
+            DatabaseEntry {
+                hidden: true,
+                ..entry(AudioSource::Jpod, "b.mp3", "")
+            },
+            entry(AudioSource::ForvoJp, "mine.mp3", "user"),
+        ];
+        let displays = |entries: Vec<DatabaseEntry>| {
+            entries
+                .into_iter()
+                .map(|e| format!("{} {}", e.file, e.display))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            displays(overlay(shipped.clone(), user.clone(), Precedence::User)),
+            ["a.mp3 user", "mine.mp3 user", "c.mp3 shipped"]
+        );
+        assert_eq!(
+            displays(overlay(shipped, user, Precedence::Shipped)),
+            ["a.mp3 shipped", "c.mp3 shipped", "mine.mp3 user"]
+        );
+    }
+
+    #[test]
+    fn pinned_first() {
+        let mut entries = vec![
+            entry(AudioSource::Nhk16, "a.mp3", "nhk16"),
+            DatabaseEntry {
+                speaker: Some("spk1".to_string()),
+                ..entry(AudioSource::ForvoJp, "本.mp3", "spk1")
+            },
+            DatabaseEntry {
+                speaker: Some("spk2".to_string()),
+                ..entry(AudioSource::ForvoJp, "本.mp3", "spk2")
+            },
+        ];
+        let pin = Pin {
+            expression: "本".to_string(),
+            reading: String::new(),
+            source: "forvo_jp".to_string(),
+            speaker: Some("spk2".to_string()),
+            file: "forvo_jp/spk2/本.mp3".to_string(),
+        };
+        apply_pin(&mut entries, &pin);
+        let displays: Vec<&str> = entries.iter().map(|e| e.display.as_str()).collect();
+        assert_eq!(displays, ["spk2", "nhk16", "spk1"]);
+    }
+
+    #[test]
+    fn blocklist() {
+        let spk = |speaker: &str, file: &str| DatabaseEntry {
+            speaker: Some(speaker.to_string()),
+            ..entry(AudioSource::ForvoJp, file, speaker)
+        };
+        let block = |expression: &str, speaker: &str, file: &str| Block {
+            expression: expression.to_string(),
+            speaker: speaker.to_string(),
+            file: file.to_string(),
+        };
+        let mut entries = vec![
+            spk("spk1", "本.mp3"),
+            spk("spk2", "本.mp3"),
+            spk("spk3", "本.mp3"),
+            spk("spk3", "日本.mp3"),
+            entry(AudioSource::Nhk16, "media/a.mp3", "nhk16"),
+        ];
+        remove_blocked(
+            &mut entries,
+            &[
+                block("", "spk1", ""),
+                block("本", "spk2", "本.mp3"),
+                block("日本", "", "本.mp3"),
+                block("", "", "a.mp3"),
+            ],
+        );
+        let displays: Vec<String> = entries
+            .iter()
+            .map(|e| format!("{} {}", e.display, e.file))
+            .collect();
+        assert_eq!(displays, ["spk3 本.mp3", "spk3 日本.mp3"]);
+        assert!(!block("本", "", "").is_valid());
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use crate::helper::is_kana;
+
+/// old/variant kanji -> the form dictionaries use, from `data/itaiji.txt`
+static ITAIJI: LazyLock<HashMap<char, char>> = LazyLock::new(|| {
+    include_str!("../data/itaiji.txt")
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| {
+            let mut chars = l.split_whitespace().map(|c| c.chars().next());
+            Some((chars.next()??, chars.next()??))
+        })
+        .collect()
+});
+
+/// the kanji of an expression in their dictionary form, with the okurigana dropped.
+/// 引越し, 引っ越し & 引越 all become 引越
+pub fn skeleton(expression: &str) -> String {
+    expression
+        .chars()
+        .filter(|c| !is_kana(*c))
+        .map(|c| ITAIJI.get(&c).copied().unwrap_or(c))
+        .collect()
+}
+
+/// whether `candidate` is another spelling of `term`. only spellings with kanji count,
+/// the reading has to be checked by the caller.
+pub fn is_variant(term: &str, candidate: &str) -> bool {
+    if term == candidate {
+        return false;
+    }
+    let skeleton = skeleton(term);
+    !skeleton.is_empty() && skeleton == self::skeleton(candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_variant, skeleton};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn variant_spellings() {
+        assert_eq!(skeleton("引っ越し"), "引越");
+        assert_eq!(skeleton("櫻"), "桜");
+        assert!(is_variant("引越し", "引っ越し"));
+        assert!(is_variant("取り扱い", "取扱"));
+        assert!(is_variant("國語", "国語"));
+        assert!(!is_variant("引越し", "引越し"));
+        assert!(!is_variant("引越し", "引き出し"));
+        // kana only words have nothing to compare
+        assert!(!is_variant("ほん", "ホン"));
+    }
+}